@@ -0,0 +1,217 @@
+use std::fmt::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::clock::*;
+use crate::packet::{as_hex, Origin};
+
+/// Raw CAN frame, for connections opened with the RP1210 CAN protocol.
+#[derive(Default, Debug, Clone)]
+pub struct CanFrame {
+    /// microseconds. Adapter clock for received and echoed frames, host clock for local frames.
+    time: u64,
+    /// host wall clock, microseconds since UNIX_EPOCH
+    wall: u64,
+    id: u32,
+    /// 29 bit identifier
+    extended: bool,
+    /// remote transmission request. The RP1210 CAN message has no RTR bit, so these can't be sent or received.
+    rtr: bool,
+    /// error frame. The RP1210 CAN message has no error flag, so this is false for received frames.
+    error: bool,
+    origin: Origin,
+    len: u8,
+    data: [u8; 8],
+}
+
+impl Display for CanFrame {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{:12.4} ", self.time())?;
+        if self.extended {
+            write!(f, "{:08X}", self.id)?;
+        } else {
+            write!(f, "{:03X}", self.id)?;
+        }
+        write!(f, " [{}]", self.len)?;
+        if !self.data().is_empty() {
+            write!(f, " {}", as_hex(self.data()))?;
+        }
+        write!(
+            f,
+            "{}{}{}",
+            if self.rtr { " RTR" } else { "" },
+            if self.error { " ERR" } else { "" },
+            if self.echo() { " (TX)" } else { "" }
+        )
+    }
+}
+
+#[allow(dead_code)]
+impl CanFrame {
+    /// Build a frame to send. IDs above 0x7FF are sent as extended (29 bit) IDs.
+    pub fn new(id: u32, data: &[u8]) -> CanFrame {
+        let len = data.len().min(8);
+        let mut buf = [0; 8];
+        buf[..len].copy_from_slice(&data[..len]);
+        CanFrame {
+            time: host_time(),
+            wall: micros_since_epoch(SystemTime::now()),
+            id: id & 0x1FFF_FFFF,
+            extended: id > 0x7FF,
+            origin: Origin::Local,
+            len: len as u8,
+            data: buf,
+            ..Default::default()
+        }
+    }
+
+    /// Force a 29 bit identifier, even for IDs that fit in 11 bits
+    pub fn extended(mut self) -> CanFrame {
+        self.extended = true;
+        self
+    }
+
+    /// Remote transmission request with the requested DLC
+    pub fn remote(id: u32, dlc: u8) -> CanFrame {
+        let mut f = CanFrame::new(id, &[]);
+        f.rtr = true;
+        f.len = dlc.min(8);
+        f
+    }
+
+    /// Parse an RP1210 CAN read buffer:
+    /// timestamp[4] echo[1] type[1] id[2 or 4, big endian] data[0..8]
+    /// The message type is only standard or extended, so RTR and error stay false.
+    pub fn new_rp1210(data: &[u8], clock: &mut AdapterClock) -> CanFrame {
+        let time = clock.extend(u32::from_be_bytes(data[0..4].try_into().unwrap()));
+        let extended = data[5] != 0;
+        let (id, rest) = if extended {
+            (
+                u32::from_be_bytes(data[6..10].try_into().unwrap()),
+                &data[10..],
+            )
+        } else {
+            (
+                u16::from_be_bytes(data[6..8].try_into().unwrap()) as u32,
+                &data[8..],
+            )
+        };
+        let len = rest.len().min(8);
+        let mut buf = [0; 8];
+        buf[..len].copy_from_slice(&rest[..len]);
+        CanFrame {
+            time,
+            wall: micros_since_epoch(clock.to_system_time(time).unwrap_or_else(SystemTime::now)),
+            id,
+            extended,
+            rtr: false,
+            error: false,
+            origin: if data[4] != 0 {
+                Origin::Echo
+            } else {
+                Origin::Bus
+            },
+            len: len as u8,
+            data: buf,
+        }
+    }
+
+    /// Write the RP1210 CAN send buffer:
+    /// type[1] id[2 or 4, big endian] data[0..8]
+    /// Returns the number of bytes written.
+    pub fn to_rp1210(&self, buf: &mut [u8]) -> usize {
+        let data = if self.rtr { &[] } else { self.data() };
+        let header = if self.extended {
+            buf[0] = 1;
+            buf[1..5].copy_from_slice(&self.id.to_be_bytes());
+            5
+        } else {
+            buf[0] = 0;
+            buf[1..3].copy_from_slice(&(self.id as u16).to_be_bytes());
+            3
+        };
+        buf[header..header + data.len()].copy_from_slice(data);
+        header + data.len()
+    }
+
+    /// milliseconds
+    pub fn time(&self) -> f64 {
+        self.time as f64 * 0.001
+    }
+    pub fn wall_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.wall)
+    }
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+    pub fn is_rtr(&self) -> bool {
+        self.rtr
+    }
+    pub fn is_error(&self) -> bool {
+        self.error
+    }
+    pub fn origin(&self) -> Origin {
+        self.origin
+    }
+    pub fn echo(&self) -> bool {
+        self.origin != Origin::Bus
+    }
+    pub fn dlc(&self) -> u8 {
+        self.len
+    }
+    pub fn data(&self) -> &[u8] {
+        if self.rtr {
+            &[]
+        } else {
+            &self.data[..self.len as usize]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard() {
+        let f = CanFrame::new(0x123, &[1, 2, 3]);
+        assert!(!f.is_extended());
+        let mut buf = [0; 16];
+        let len = f.to_rp1210(&mut buf);
+        assert_eq!(&[0, 0x01, 0x23, 1, 2, 3], &buf[..len]);
+
+        let read = [&[0, 0, 0, 10, 0], &buf[..len]].concat();
+        let r = CanFrame::new_rp1210(&read, &mut AdapterClock::new(1000.0));
+        assert_eq!(0x123, r.id());
+        assert_eq!(&[1, 2, 3], r.data());
+        assert_eq!(Origin::Bus, r.origin());
+        assert_eq!("     10.0000 123 [3] 01 02 03", r.to_string());
+    }
+
+    #[test]
+    fn extended() {
+        let f = CanFrame::new(0x42, &[0xFF; 8]).extended();
+        let mut buf = [0; 16];
+        let len = f.to_rp1210(&mut buf);
+        assert_eq!(&[1, 0, 0, 0, 0x42], &buf[..5]);
+        assert_eq!(13, len);
+
+        let read = [&[0, 0, 0, 0, 1], &buf[..len]].concat();
+        let r = CanFrame::new_rp1210(&read, &mut AdapterClock::new(1.0));
+        assert!(r.is_extended());
+        assert_eq!(0x42, r.id());
+        assert_eq!(8, r.dlc());
+        assert!(r.echo());
+    }
+
+    #[test]
+    fn remote() {
+        let f = CanFrame::remote(0x7FF, 4);
+        assert!(f.is_rtr());
+        assert_eq!(4, f.dlc());
+        assert!(f.data().is_empty());
+        assert_eq!(" 7FF [4] RTR (TX)", &f.to_string()[12..]);
+    }
+}
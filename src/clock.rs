@@ -0,0 +1,234 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Extends the adapter's 32 bit RP1210 timestamps to 64 bits and correlates them with host time.
+///
+/// One clock per adapter connection. Timestamps are returned in microseconds.
+#[derive(Debug, Clone)]
+pub struct AdapterClock {
+    /// microseconds per tick (TimeStampWeight from the vendor INI)
+    weight: f64,
+    /// latest extended timestamp in ticks and the host time it was read
+    last: Option<(u64, Instant)>,
+    /// first correlation point: adapter µs, host wall clock, host monotonic clock
+    origin: Option<(u64, SystemTime, Instant)>,
+    /// latest correlation point: adapter µs, host monotonic clock
+    latest: Option<(u64, Instant)>,
+    /// added to the adapter time so it continues across a reconnect
+    offset: i64,
+    restarted: bool,
+}
+
+#[allow(dead_code)]
+impl AdapterClock {
+    pub fn new(time_stamp_weight: f64) -> AdapterClock {
+        AdapterClock {
+            weight: time_stamp_weight,
+            last: None,
+            origin: None,
+            latest: None,
+            offset: 0,
+            restarted: false,
+        }
+    }
+
+    /// The adapter was reconnected and its timestamps may have started over.
+    /// Times keep increasing from the last one seen, plus the host time spent disconnected.
+    pub fn restart(&mut self) {
+        self.last = None;
+        self.restarted = true;
+    }
+
+    /// Extend a raw timestamp that was just read from the adapter. Returns adapter time in µs.
+    pub fn extend(&mut self, raw: u32) -> u64 {
+        self.extend_at(raw, Instant::now())
+    }
+
+    fn extend_at(&mut self, raw: u32, host: Instant) -> u64 {
+        let ticks = match self.last {
+            None => raw as u64,
+            Some((last, at)) => {
+                // the host clock says about where the counter is after a quiet period, however many
+                // times it wrapped. The nearest value with these low 32 bits is then right to within
+                // half the range, which also covers packets slightly out of order (echo vs rx).
+                let elapsed = host.saturating_duration_since(at).as_micros() as f64 / self.weight;
+                let expected = last + elapsed as u64;
+                let candidate = (expected & !0xFFFF_FFFF) | raw as u64;
+                [
+                    candidate.checked_sub(1 << 32),
+                    Some(candidate),
+                    Some(candidate + (1 << 32)),
+                ]
+                .into_iter()
+                .flatten()
+                .min_by_key(|t| t.abs_diff(expected))
+                .unwrap()
+            }
+        };
+        // a late packet does not move the clock back
+        if self.last.is_none_or(|(last, _)| ticks > last) {
+            self.last = Some((ticks, host));
+        }
+        let adapter = (ticks as f64 * self.weight) as u64;
+        if self.restarted {
+            self.restarted = false;
+            let resume = self
+                .latest
+                .map(|(t, at)| t + host.saturating_duration_since(at).as_micros() as u64)
+                .unwrap_or(adapter);
+            self.offset = resume as i64 - adapter as i64;
+        }
+        let time = (adapter as i64 + self.offset).max(0) as u64;
+
+        if self.origin.is_none() {
+            self.origin = Some((time, SystemTime::now(), host));
+        }
+        if self.latest.is_none_or(|(t, _)| time > t) {
+            self.latest = Some((time, host));
+        }
+        time
+    }
+
+    /// Host wall clock time for an adapter timestamp (µs)
+    pub fn to_system_time(&self, time: u64) -> Option<SystemTime> {
+        self.origin.map(|(origin, wall, _)| {
+            if time >= origin {
+                wall + Duration::from_micros(time - origin)
+            } else {
+                wall - Duration::from_micros(origin - time)
+            }
+        })
+    }
+
+    /// Estimated drift of the adapter clock relative to the host clock, in parts per million.
+    /// Positive when the adapter clock runs fast. None until at least a second has been observed.
+    pub fn drift_ppm(&self) -> Option<f64> {
+        let (origin, _, host_origin) = self.origin?;
+        let (latest, host_latest) = self.latest?;
+        let host = host_latest.duration_since(host_origin).as_micros() as f64;
+        if host < 1_000_000.0 {
+            return None;
+        }
+        let adapter = (latest - origin) as f64;
+        Some((adapter - host) / host * 1_000_000.0)
+    }
+}
+
+/// Host monotonic time in µs since the first call. Used to timestamp packets built locally.
+pub fn host_time() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+pub fn micros_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Format as ISO 8601 UTC with microseconds, i.e. 2023-01-02T03:04:05.000006Z
+pub fn format_system_time(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (h, m, s) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+    // civil from days, Howard Hinnant's algorithm
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let mo = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if mo <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        y,
+        mo,
+        d,
+        h,
+        m,
+        s,
+        since.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollover() {
+        let mut clock = AdapterClock::new(1.0);
+        let now = Instant::now();
+        assert_eq!(0xFFFF_FFF0, clock.extend_at(0xFFFF_FFF0, now));
+        assert_eq!(0x1_0000_0010, clock.extend_at(0x10, now));
+        // late packet from before the wrap
+        assert_eq!(0xFFFF_FFF8, clock.extend_at(0xFFFF_FFF8, now));
+        // slightly out of order after the wrap
+        assert_eq!(0x1_0000_0008, clock.extend_at(0x08, now));
+        assert_eq!(0x1_0000_0020, clock.extend_at(0x20, now));
+    }
+
+    #[test]
+    fn long_gaps() {
+        let mut clock = AdapterClock::new(1.0);
+        let start = Instant::now();
+        assert_eq!(1000, clock.extend_at(1000, start));
+        // 40 minutes without a frame is more than half the 32 bit range of µs, but not a wrap
+        let t = 1000 + 40 * 60 * 1_000_000;
+        let later = start + Duration::from_secs(40 * 60);
+        assert_eq!(t, clock.extend_at(t as u32, later));
+        // slightly out of order
+        assert_eq!(t - 10, clock.extend_at((t - 10) as u32, later));
+        // 2 hours more is two wraps, with the adapter clock 500 ms (70 ppm) ahead of the host
+        let t = t + 2 * 3600 * 1_000_000 + 500_000;
+        let later = later + Duration::from_secs(2 * 3600);
+        assert_eq!(t, clock.extend_at(t as u32, later));
+        assert_eq!(t + 100, clock.extend_at((t + 100) as u32, later));
+    }
+
+    #[test]
+    fn restart() {
+        let mut clock = AdapterClock::new(1.0);
+        let start = Instant::now();
+        assert_eq!(5_000_000, clock.extend_at(5_000_000, start));
+        clock.restart();
+        // adapter started counting from 0 again after 2 s disconnected
+        let later = start + Duration::from_secs(2);
+        assert_eq!(7_000_000, clock.extend_at(0, later));
+        assert_eq!(7_000_100, clock.extend_at(100, later));
+    }
+
+    #[test]
+    fn weight() {
+        let mut clock = AdapterClock::new(1000.0);
+        assert_eq!(5_000, clock.extend(5));
+    }
+
+    #[test]
+    fn drift() {
+        let mut clock = AdapterClock::new(1.0);
+        let start = Instant::now();
+        clock.extend_at(0, start);
+        assert_eq!(None, clock.drift_ppm());
+        // adapter counted 10 ms extra over 10 s
+        clock.extend_at(10_010_000, start + Duration::from_secs(10));
+        assert!((clock.drift_ppm().unwrap() - 1000.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn system_time() {
+        let mut clock = AdapterClock::new(1.0);
+        clock.extend(1_000_000);
+        let origin = clock.to_system_time(1_000_000).unwrap();
+        assert_eq!(
+            origin + Duration::from_millis(1500),
+            clock.to_system_time(2_500_000).unwrap()
+        );
+        assert_eq!(
+            "2023-01-02T03:04:05.000006Z",
+            format_system_time(UNIX_EPOCH + Duration::from_micros(1_672_628_645_000_006))
+        );
+    }
+}
@@ -0,0 +1,489 @@
+use anyhow::*;
+use std::time::Duration;
+
+/// RP1210_SendCommand numbers
+pub const CMD_RESET_DEVICE: u16 = 0;
+pub const CMD_SET_ALL_FILTERS_STATES_TO_PASS: u16 = 3;
+pub const CMD_SET_MESSAGE_FILTERING_FOR_J1939: u16 = 4;
+pub const CMD_SET_MESSAGE_FILTERING_FOR_CAN: u16 = 5;
+pub const CMD_SET_MESSAGE_FILTERING_FOR_J1708: u16 = 7;
+pub const CMD_ECHO_TRANSMITTED_MESSAGES: u16 = 16;
+pub const CMD_SET_ALL_FILTERS_STATES_TO_DISCARD: u16 = 17;
+pub const CMD_PROTECT_J1939_ADDRESS: u16 = 19;
+pub const CMD_RELEASE_J1939_ADDRESS: u16 = 31;
+pub const CMD_SET_J1939_BAUD: u16 = 37;
+pub const CMD_FLUSH_TX_RX_BUFFERS: u16 = 39;
+pub const CMD_SET_BLOCKING_TIMEOUT: u16 = 215;
+
+/// J1939 filter flags
+const FILTER_PGN: u8 = 0x01;
+const FILTER_PRIORITY: u8 = 0x02;
+const FILTER_SOURCE: u8 = 0x04;
+const FILTER_DESTINATION: u8 = 0x08;
+
+/// A validated RP1210_SendCommand: command number and client command buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    pub number: u16,
+    pub data: Vec<u8>,
+}
+
+impl Command {
+    fn new(number: u16, data: Vec<u8>) -> Command {
+        Command { number, data }
+    }
+
+    pub fn all_filters_pass() -> Command {
+        Command::new(CMD_SET_ALL_FILTERS_STATES_TO_PASS, vec![])
+    }
+
+    pub fn echo(on: bool) -> Command {
+        Command::new(CMD_ECHO_TRANSMITTED_MESSAGES, vec![on as u8])
+    }
+
+    /// Client state the adapter forgets on disconnect, so it is re-applied after a reconnect
+    pub fn persistent(&self) -> bool {
+        !matches!(self.number, CMD_RESET_DEVICE | CMD_FLUSH_TX_RX_BUFFERS)
+    }
+}
+
+// Builders for the rest of RP1210C's commands. Nothing in the CLI sends these yet.
+#[allow(dead_code)]
+impl Command {
+    /// Reset the adapter. Only allowed when this is the only client.
+    pub fn reset_device() -> Command {
+        Command::new(CMD_RESET_DEVICE, vec![])
+    }
+
+    pub fn all_filters_discard() -> Command {
+        Command::new(CMD_SET_ALL_FILTERS_STATES_TO_DISCARD, vec![])
+    }
+
+    pub fn release_j1939_address(address: u8) -> Result<Command> {
+        if address > 253 {
+            bail!("Cannot release J1939 address {}", address);
+        }
+        Ok(Command::new(CMD_RELEASE_J1939_ADDRESS, vec![address]))
+    }
+
+    /// Change the J1939 baud rate immediately
+    pub fn set_j1939_baud(baud: Baud) -> Result<Command> {
+        if baud.code() < Baud::B125K.code() {
+            bail!("{:?} is not a J1939 baud rate", baud);
+        }
+        Ok(Command::new(CMD_SET_J1939_BAUD, vec![0, baud.code()]))
+    }
+
+    /// How long blocking reads and sends wait. RP1210 sends two byte factors whose product is the
+    /// timeout in milliseconds, so it has to be whole ms that factor into two numbers up to 255.
+    pub fn set_blocking_timeout(timeout: Duration) -> Result<Command> {
+        let ms = timeout.as_millis();
+        let factor = (1..=255u128)
+            .find(|f1| ms.is_multiple_of(*f1) && (1..=255).contains(&(ms / f1)))
+            .filter(|_| timeout.subsec_nanos().is_multiple_of(1_000_000));
+        match factor {
+            Some(f1) => Ok(Command::new(
+                CMD_SET_BLOCKING_TIMEOUT,
+                vec![f1 as u8, (ms / f1) as u8],
+            )),
+            None => bail!(
+                "Blocking timeout {:?} is not a product of two factors from 1 to 255 ms",
+                timeout
+            ),
+        }
+    }
+
+    /// Discard everything queued for transmit and everything not yet read
+    pub fn flush_tx_rx_buffers() -> Command {
+        Command::new(CMD_FLUSH_TX_RX_BUFFERS, vec![])
+    }
+}
+
+/// RP1210 baud rate codes
+// set_j1939_baud's argument, with every code RP1210C defines
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Baud {
+    B9600,
+    B19200,
+    B38400,
+    B57600,
+    B125K,
+    B250K,
+    B500K,
+    B1000K,
+}
+
+impl Baud {
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// CMD_PROTECT_J1939_ADDRESS builder
+#[derive(Debug, Clone)]
+pub struct ProtectAddress {
+    address: u8,
+    name: u64,
+    block: bool,
+}
+
+impl ProtectAddress {
+    /// Claim address with a default NAME (arbitrary address capable, industry group 0)
+    pub fn new(address: u8) -> ProtectAddress {
+        ProtectAddress {
+            address,
+            name: 0x0000_8100_FFE0_0000,
+            block: false,
+        }
+    }
+    /// 64 bit J1939 NAME
+    // address claims use the default NAME
+    #[allow(dead_code)]
+    pub fn name(mut self, name: u64) -> ProtectAddress {
+        self.name = name;
+        self
+    }
+    /// Block until the address claim completes
+    pub fn block_until_done(mut self) -> ProtectAddress {
+        self.block = true;
+        self
+    }
+    pub fn build(self) -> Result<Command> {
+        if self.address > 253 {
+            bail!("Cannot claim J1939 address {}", self.address);
+        }
+        let mut data = vec![self.address];
+        // NAME is sent least significant byte first, as in the address claim PG
+        data.extend_from_slice(&self.name.to_le_bytes());
+        // CLAIM_BLOCK_UNTIL_DONE is 0, RETURN_BEFORE_COMPLETION is 2
+        data.push(if self.block { 0 } else { 2 });
+        Ok(Command::new(CMD_PROTECT_J1939_ADDRESS, data))
+    }
+}
+
+/// One entry of CMD_SET_MESSAGE_FILTERING_FOR_J1939. Unset fields match anything.
+#[derive(Debug, Clone, Default)]
+pub struct J1939MessageFilter {
+    pgn: Option<u32>,
+    priority: Option<u8>,
+    source: Option<u8>,
+    dest: Option<u8>,
+}
+
+// log and the bandwidth tests read every PGN
+#[allow(dead_code)]
+impl J1939MessageFilter {
+    pub fn new() -> J1939MessageFilter {
+        Default::default()
+    }
+    pub fn pgn(mut self, pgn: u32) -> J1939MessageFilter {
+        self.pgn = Some(pgn);
+        self
+    }
+    pub fn priority(mut self, priority: u8) -> J1939MessageFilter {
+        self.priority = Some(priority);
+        self
+    }
+    pub fn source(mut self, source: u8) -> J1939MessageFilter {
+        self.source = Some(source);
+        self
+    }
+    pub fn dest(mut self, dest: u8) -> J1939MessageFilter {
+        self.dest = Some(dest);
+        self
+    }
+
+    /// flags[1] pgn[3, little endian] priority[1] source[1] dest[1]
+    fn encode(&self) -> Result<[u8; 7]> {
+        if self.pgn.is_some_and(|pgn| pgn > 0x3FFFF) {
+            bail!("Invalid PGN in filter {:?}", self);
+        }
+        if self.priority.is_some_and(|p| p > 7) {
+            bail!("Invalid priority in filter {:?}", self);
+        }
+        let flags = self.pgn.map_or(0, |_| FILTER_PGN)
+            | self.priority.map_or(0, |_| FILTER_PRIORITY)
+            | self.source.map_or(0, |_| FILTER_SOURCE)
+            | self.dest.map_or(0, |_| FILTER_DESTINATION);
+        if flags == 0 {
+            bail!("J1939 filter must set at least one field");
+        }
+        let pgn = self.pgn.unwrap_or(0).to_le_bytes();
+        Ok([
+            flags,
+            pgn[0],
+            pgn[1],
+            pgn[2],
+            self.priority.unwrap_or(0),
+            self.source.unwrap_or(0),
+            self.dest.unwrap_or(0),
+        ])
+    }
+
+    /// Command passing any of the filters
+    pub fn build(filters: &[J1939MessageFilter]) -> Result<Command> {
+        if filters.is_empty() {
+            bail!("No J1939 filters");
+        }
+        let mut data = Vec::with_capacity(filters.len() * 7);
+        for f in filters {
+            data.extend_from_slice(&f.encode()?);
+        }
+        Ok(Command::new(CMD_SET_MESSAGE_FILTERING_FOR_J1939, data))
+    }
+}
+
+/// One entry of CMD_SET_MESSAGE_FILTERING_FOR_CAN: pass IDs where id & mask == header
+#[derive(Debug, Clone)]
+pub struct CanMessageFilter {
+    extended: bool,
+    mask: u32,
+    header: u32,
+}
+
+// raw CAN mode reads every ID
+#[allow(dead_code)]
+impl CanMessageFilter {
+    pub fn standard(mask: u32, header: u32) -> CanMessageFilter {
+        CanMessageFilter {
+            extended: false,
+            mask,
+            header,
+        }
+    }
+    pub fn extended(mask: u32, header: u32) -> CanMessageFilter {
+        CanMessageFilter {
+            extended: true,
+            mask,
+            header,
+        }
+    }
+
+    /// type[1] mask[4, big endian] header[4, big endian]
+    fn encode(&self) -> Result<[u8; 9]> {
+        let max = if self.extended { 0x1FFF_FFFF } else { 0x7FF };
+        if self.mask > max || self.header > max {
+            bail!("CAN filter out of range: {:?}", self);
+        }
+        if self.header & !self.mask != 0 {
+            bail!("CAN filter header has bits outside the mask: {:?}", self);
+        }
+        let mut rtn = [0; 9];
+        rtn[0] = self.extended as u8;
+        rtn[1..5].copy_from_slice(&self.mask.to_be_bytes());
+        rtn[5..9].copy_from_slice(&self.header.to_be_bytes());
+        Ok(rtn)
+    }
+
+    pub fn build(filters: &[CanMessageFilter]) -> Result<Command> {
+        if filters.is_empty() {
+            bail!("No CAN filters");
+        }
+        let mut data = Vec::with_capacity(filters.len() * 9);
+        for f in filters {
+            data.extend_from_slice(&f.encode()?);
+        }
+        Ok(Command::new(CMD_SET_MESSAGE_FILTERING_FOR_CAN, data))
+    }
+}
+
+/// CMD_SET_MESSAGE_FILTERING_FOR_J1708: pass these MIDs
+// J1708 mode reads every MID
+#[allow(dead_code)]
+pub fn j1708_message_filter(mids: &[u8]) -> Result<Command> {
+    if mids.is_empty() {
+        bail!("No J1708 MIDs to filter on");
+    }
+    let mut mids = mids.to_vec();
+    mids.sort_unstable();
+    mids.dedup();
+    Ok(Command::new(CMD_SET_MESSAGE_FILTERING_FOR_J1708, mids))
+}
+
+/// Internal queue size limits. RP1210C has no standard command for these, so the
+/// vendor's command number must be supplied.
+#[derive(Debug, Clone, Default)]
+pub struct QueueSize {
+    command: Option<u16>,
+    size: Option<u32>,
+}
+
+// vendor specific, so only for callers that know their adapter's command
+#[allow(dead_code)]
+impl QueueSize {
+    pub fn new() -> QueueSize {
+        Default::default()
+    }
+    /// vendor command number from the adapter documentation
+    pub fn command(mut self, number: u16) -> QueueSize {
+        self.command = Some(number);
+        self
+    }
+    /// queue size in messages
+    pub fn size(mut self, size: u32) -> QueueSize {
+        self.size = Some(size);
+        self
+    }
+    pub fn build(self) -> Result<Command> {
+        let number = self
+            .command
+            .ok_or_else(|| anyhow!("Queue size requires the vendor command number"))?;
+        let size = self
+            .size
+            .filter(|s| *s > 0)
+            .ok_or_else(|| anyhow!("Queue size must be at least 1"))?;
+        Ok(Command::new(number, size.to_le_bytes().to_vec()))
+    }
+}
+
+/// Status of one protocol from RP1210_GetHardwareStatus
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtocolStatus {
+    pub connected: bool,
+    pub activity: bool,
+    pub bus_off: bool,
+    pub error_passive: bool,
+    pub clients: u8,
+}
+
+/// RP1210_GetHardwareStatus buffer: two bytes each for the hardware,
+/// J1939, J1708, CAN, J1850 and ISO15765.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HardwareStatus {
+    pub device_valid: bool,
+    pub device_located: bool,
+    pub j1939: ProtocolStatus,
+    pub j1708: ProtocolStatus,
+    pub can: ProtocolStatus,
+    pub iso15765: ProtocolStatus,
+}
+
+/// bytes read by RP1210_GetHardwareStatus
+pub const HARDWARE_STATUS_SIZE: usize = 18;
+
+impl HardwareStatus {
+    pub fn parse(buf: &[u8]) -> Result<HardwareStatus> {
+        if buf.len() < 12 {
+            bail!("Hardware status too short: {:02X?}", buf);
+        }
+        let protocol = |i: usize| ProtocolStatus {
+            connected: buf[i] & 0x01 != 0,
+            activity: buf[i] & 0x02 != 0,
+            bus_off: buf[i] & 0x04 != 0,
+            error_passive: buf[i] & 0x08 != 0,
+            clients: buf[i + 1],
+        };
+        Ok(HardwareStatus {
+            device_valid: buf[0] & 0x01 != 0,
+            device_located: buf[0] & 0x02 != 0,
+            j1939: protocol(2),
+            j1708: protocol(4),
+            can: protocol(6),
+            iso15765: protocol(10),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple() {
+        assert_eq!(Command::new(0, vec![]), Command::reset_device());
+        assert_eq!(vec![1], Command::echo(true).data);
+        assert_eq!(
+            vec![0, 5],
+            Command::set_j1939_baud(Baud::B250K).unwrap().data
+        );
+        assert!(Command::set_j1939_baud(Baud::B9600).is_err());
+        assert!(Command::release_j1939_address(254).is_err());
+        assert_eq!(
+            vec![4, 250],
+            Command::set_blocking_timeout(Duration::from_secs(1))
+                .unwrap()
+                .data
+        );
+        assert_eq!(
+            vec![1, 100],
+            Command::set_blocking_timeout(Duration::from_millis(100))
+                .unwrap()
+                .data
+        );
+        assert!(Command::set_blocking_timeout(Duration::ZERO).is_err());
+        // prime
+        assert!(Command::set_blocking_timeout(Duration::from_millis(257)).is_err());
+        assert!(Command::set_blocking_timeout(Duration::from_millis(65026)).is_err());
+        assert!(Command::set_blocking_timeout(Duration::from_micros(1500)).is_err());
+        assert!(Command::all_filters_discard().persistent());
+        assert!(!Command::reset_device().persistent());
+        assert!(!Command::flush_tx_rx_buffers().persistent());
+    }
+
+    #[test]
+    fn protect_address() {
+        let c = ProtectAddress::new(0xF9)
+            .block_until_done()
+            .build()
+            .unwrap();
+        assert_eq!(CMD_PROTECT_J1939_ADDRESS, c.number);
+        assert_eq!(vec![0xF9, 0, 0, 0xE0, 0xFF, 0, 0x81, 0, 0, 0], c.data);
+        assert!(ProtectAddress::new(0xFE).build().is_err());
+    }
+
+    #[test]
+    fn filters() {
+        let c = J1939MessageFilter::build(&[
+            J1939MessageFilter::new().pgn(0xFEF1).source(0),
+            J1939MessageFilter::new().dest(0xF9),
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![0x05, 0xF1, 0xFE, 0, 0, 0, 0, 0x08, 0, 0, 0, 0, 0, 0xF9],
+            c.data
+        );
+        assert!(J1939MessageFilter::build(&[]).is_err());
+        assert!(J1939MessageFilter::build(&[J1939MessageFilter::new()]).is_err());
+        assert!(J1939MessageFilter::build(&[J1939MessageFilter::new().priority(8)]).is_err());
+
+        let c = CanMessageFilter::build(&[CanMessageFilter::standard(0x7F0, 0x120)]).unwrap();
+        assert_eq!(vec![0, 0, 0, 0x07, 0xF0, 0, 0, 0x01, 0x20], c.data);
+        assert!(CanMessageFilter::build(&[CanMessageFilter::standard(0x7F0, 0x121)]).is_err());
+        assert!(CanMessageFilter::build(&[CanMessageFilter::standard(0x800, 0)]).is_err());
+
+        assert_eq!(
+            vec![128, 140],
+            j1708_message_filter(&[140, 128, 140]).unwrap().data
+        );
+        assert!(QueueSize::new().size(100).build().is_err());
+        assert_eq!(
+            vec![100, 0, 0, 0],
+            QueueSize::new()
+                .command(250)
+                .size(100)
+                .build()
+                .unwrap()
+                .data
+        );
+    }
+
+    #[test]
+    fn hardware_status() {
+        let s = HardwareStatus::parse(&[0x03, 0, 0x03, 2, 0, 0, 0x05, 1, 0, 0, 0, 0]).unwrap();
+        assert!(s.device_valid && s.device_located);
+        assert_eq!(
+            ProtocolStatus {
+                connected: true,
+                activity: true,
+                bus_off: false,
+                error_passive: false,
+                clients: 2
+            },
+            s.j1939
+        );
+        assert!(s.can.bus_off);
+        assert!(!s.j1708.connected);
+        assert!(HardwareStatus::parse(&[0; 4]).is_err());
+    }
+}
@@ -0,0 +1,645 @@
+use anyhow::*;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU8, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+
+use crate::j1939_21::transfer_time;
+use crate::multiqueue::MqIter;
+use crate::packet::{is_pdu1, J1939Filter, J1939Packet};
+use crate::pattern::{Pattern, MAX_LENGTH};
+use crate::rp1210::Rp1210;
+use crate::stats::RxSummary;
+
+/// Test control protocol between a client and the server subcommand, on a proprietary PGN.
+///
+/// Every message is cmd[1] version[1] token[1] session[1] payload, padded to 8 bytes.
+/// Longer messages go out with the adapter's transport protocol.
+/// Commands are answered with ACK or ERROR carrying the same token, PING with the PING itself.
+/// session is the client's address in both directions, so clients sharing a PDU2 PGN can tell
+/// their replies and DATA frames apart.
+///
+/// Version 2 added session. Version 1 carried the acknowledged command there.
+/// Version 3 added payload pattern, length, seed and data PGN to the test parameters and corrupt frames to the summary.
+/// Version 4 added the receiver's idle timeout to the test parameters.
+pub const VERSION: u8 = 4;
+
+pub const PING_CMD: u8 = 1;
+pub const RX_CMD: u8 = 2;
+pub const TX_CMD: u8 = 3;
+pub const DATA_CMD: u8 = 4;
+pub const EXIT_CMD: u8 = 5;
+pub const SUMMARY_CMD: u8 = 6;
+pub const HELLO_CMD: u8 = 7;
+pub const ACK_CMD: u8 = 8;
+pub const ERROR_CMD: u8 = 9;
+pub const DUPLEX_CMD: u8 = 10;
+
+/// HELLO capability bits
+pub const CAP_PING: u32 = 0x01;
+pub const CAP_BANDWIDTH: u32 = 0x02;
+pub const CAP_SUMMARY: u32 = 0x04;
+pub const CAP_DUPLEX: u32 = 0x08;
+/// everything this build's server supports
+pub const CAPABILITIES: u32 = CAP_PING | CAP_BANDWIDTH | CAP_SUMMARY | CAP_DUPLEX;
+
+/// Wait for ACK or ERROR. Long enough for a BAM of a few packets.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const HEADER: usize = 4;
+/// index of the session byte
+const SESSION: usize = 3;
+
+/// ERROR reply codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnknownCommand,
+    Version,
+    Malformed,
+    Unsupported,
+    Other(u8),
+}
+
+impl From<u8> for ErrorCode {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => ErrorCode::UnknownCommand,
+            2 => ErrorCode::Version,
+            3 => ErrorCode::Malformed,
+            4 => ErrorCode::Unsupported,
+            c => ErrorCode::Other(c),
+        }
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::UnknownCommand => 1,
+            ErrorCode::Version => 2,
+            ErrorCode::Malformed => 3,
+            ErrorCode::Unsupported => 4,
+            ErrorCode::Other(c) => c,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCode::UnknownCommand => write!(f, "unknown command"),
+            ErrorCode::Version => write!(f, "protocol version mismatch"),
+            ErrorCode::Malformed => write!(f, "malformed message"),
+            ErrorCode::Unsupported => write!(f, "unsupported test parameters"),
+            ErrorCode::Other(c) => write!(f, "error {}", c),
+        }
+    }
+}
+
+/// How long the receiver of a bandwidth test waits for the next frame by default
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Bandwidth test parameters, agreed before the first DATA frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestParams {
+    pub count: u32,
+    /// frames/s, None for as fast as possible
+    pub rate: Option<f64>,
+    pub pattern: Pattern,
+    /// payload bytes. Over 8 goes out with the adapter's transport protocol.
+    pub length: u16,
+    /// for Pattern::Random
+    pub seed: u32,
+    /// PGN of the test frames when it is not the control PGN.
+    /// Required for every pattern but Sequence, which is the only one a control message can't be mistaken for.
+    pub data_pgn: Option<u32>,
+    /// how long the receiver waits for the next frame before it reports what it has, in ms on the wire
+    pub idle: Duration,
+}
+
+impl Default for TestParams {
+    fn default() -> Self {
+        TestParams {
+            count: 0,
+            rate: None,
+            pattern: Pattern::Sequence,
+            length: 8,
+            seed: 0,
+            data_pgn: None,
+            idle: IDLE_TIMEOUT,
+        }
+    }
+}
+
+/// data_pgn encoding for None
+const NO_PGN: u32 = 0xFFFF_FFFF;
+
+impl TestParams {
+    /// Parameters this build can run on control_pgn
+    pub fn validate(&self, control_pgn: u32) -> Result<(), ErrorCode> {
+        let min = if self.pattern == Pattern::Sequence {
+            8
+        } else if self.pgn(control_pgn) == control_pgn {
+            return Err(ErrorCode::Unsupported);
+        } else {
+            0
+        };
+        if (self.length as usize) < min || self.length as usize > MAX_LENGTH {
+            return Err(ErrorCode::Unsupported);
+        }
+        Result::Ok(())
+    }
+
+    /// idle plus the time to send one message, which is long for a transport message
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle + transfer_time(self.length as usize)
+    }
+
+    /// PGN the test frames are sent on
+    pub fn pgn(&self, control_pgn: u32) -> u32 {
+        self.data_pgn.unwrap_or(control_pgn)
+    }
+
+    /// Test frames from source. A PDU1 data PGN matches any destination, it is the receiver's address.
+    pub fn data_filter(&self, control_pgn: u32, source: u8) -> J1939Filter {
+        let pgn = self.pgn(control_pgn);
+        let mask = if is_pdu1(pgn) { 0x3FF00 } else { 0x3FFFF };
+        J1939Filter::new().pgn_mask(pgn, mask).source(source)
+    }
+
+    /// Test frame seq in the session of client address session
+    pub fn payload(&self, session: u8, seq: u32) -> Vec<u8> {
+        if self.pattern == Pattern::Sequence {
+            let mut rtn = Message::Data { seq }.encode(session, 0);
+            rtn.resize(self.length as usize, 0);
+            rtn
+        } else {
+            self.pattern.payload(self.seed, seq, self.length as usize)
+        }
+    }
+
+    /// Sequence number of a received test frame, expected next, and whether the whole payload is intact.
+    /// None for other messages on the control PGN.
+    pub fn check(&self, session: u8, data: &[u8], next: u32) -> Option<(u32, bool)> {
+        if self.pattern == Pattern::Sequence {
+            if data.len() < 8 || data[0] != DATA_CMD {
+                return None;
+            }
+            let seq = u32::from_be_bytes(data[4..8].try_into().unwrap());
+            Some((seq, data == self.payload(session, seq)))
+        } else {
+            Some(
+                self.pattern
+                    .find(self.seed, data, next)
+                    .map_or((next, false), |seq| (seq, true)),
+            )
+        }
+    }
+
+    /// count:u32 rate:u32 mHz (0 for as fast as possible) pattern:u8 length:u16 seed:u32 data_pgn:u32 idle:u32 ms
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.count.to_be_bytes());
+        let rate = self
+            .rate
+            .map_or(0, |r| (r * 1000.0).round().max(1.0) as u32);
+        buf.extend_from_slice(&rate.to_be_bytes());
+        buf.push(self.pattern.into());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+        buf.extend_from_slice(&self.seed.to_be_bytes());
+        buf.extend_from_slice(&self.data_pgn.unwrap_or(NO_PGN).to_be_bytes());
+        let idle = u32::try_from(self.idle.as_millis()).unwrap_or(u32::MAX);
+        buf.extend_from_slice(&idle.to_be_bytes());
+    }
+
+    fn parse(data: &[u8]) -> Result<TestParams, ErrorCode> {
+        if data.len() < 23 {
+            return Err(ErrorCode::Malformed);
+        }
+        let u32_at = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+        let rate = u32_at(4);
+        let data_pgn = u32_at(15);
+        Result::Ok(TestParams {
+            count: u32_at(0),
+            rate: if rate == 0 {
+                None
+            } else {
+                Some(rate as f64 / 1000.0)
+            },
+            // a pattern from a newer build
+            pattern: data[8].try_into().map_err(|_| ErrorCode::Unsupported)?,
+            length: u16::from_be_bytes([data[9], data[10]]),
+            seed: u32_at(11),
+            data_pgn: if data_pgn == NO_PGN {
+                None
+            } else {
+                Some(data_pgn)
+            },
+            idle: Duration::from_millis(u32_at(19) as u64),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// echoed back unchanged by the server
+    Ping {
+        seq: u32,
+    },
+    /// server receives DATA frames from the client
+    Rx(TestParams),
+    /// server sends DATA frames to the client
+    Tx(TestParams),
+    /// both at once, the server's SUMMARY follows
+    Duplex(TestParams),
+    Data {
+        seq: u32,
+    },
+    Exit,
+    /// what the receiver saw, token of the RX command
+    Summary(RxSummary),
+    /// handshake with the client's capabilities
+    Hello {
+        capabilities: u32,
+    },
+    /// HELLO acks carry the server's capabilities in value
+    Ack {
+        value: u32,
+    },
+    Error {
+        command: u8,
+        code: ErrorCode,
+    },
+}
+
+impl Message {
+    pub fn command(&self) -> u8 {
+        match self {
+            Message::Ping { .. } => PING_CMD,
+            Message::Rx(_) => RX_CMD,
+            Message::Tx(_) => TX_CMD,
+            Message::Duplex(_) => DUPLEX_CMD,
+            Message::Data { .. } => DATA_CMD,
+            Message::Exit => EXIT_CMD,
+            Message::Summary(_) => SUMMARY_CMD,
+            Message::Hello { .. } => HELLO_CMD,
+            Message::Ack { .. } => ACK_CMD,
+            Message::Error { .. } => ERROR_CMD,
+        }
+    }
+
+    /// session is the client's address
+    pub fn encode(&self, session: u8, token: u8) -> Vec<u8> {
+        let mut rtn = vec![self.command(), VERSION, token, session];
+        match self {
+            Message::Ping { seq } | Message::Data { seq } => {
+                rtn.extend_from_slice(&seq.to_be_bytes())
+            }
+            Message::Rx(params) | Message::Tx(params) | Message::Duplex(params) => {
+                params.encode(&mut rtn)
+            }
+            Message::Summary(summary) => rtn.extend(summary.encode()),
+            Message::Hello { capabilities } => rtn.extend_from_slice(&capabilities.to_be_bytes()),
+            Message::Ack { value } => rtn.extend_from_slice(&value.to_be_bytes()),
+            Message::Error { command, code } => rtn.extend([(*code).into(), *command]),
+            Message::Exit => {}
+        }
+        if rtn.len() < 8 {
+            rtn.resize(8, 0);
+        }
+        rtn
+    }
+
+    /// token and message, or the code for an ERROR reply
+    pub fn parse(data: &[u8]) -> Result<(u8, Message), ErrorCode> {
+        if data.len() < 8 {
+            return Err(ErrorCode::Malformed);
+        }
+        if data[1] != VERSION {
+            return Err(ErrorCode::Version);
+        }
+        let (token, payload) = (data[2], &data[HEADER..]);
+        let u32_at = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+        let message = match data[0] {
+            PING_CMD => Message::Ping { seq: u32_at(0) },
+            RX_CMD => Message::Rx(TestParams::parse(payload)?),
+            TX_CMD => Message::Tx(TestParams::parse(payload)?),
+            DUPLEX_CMD => Message::Duplex(TestParams::parse(payload)?),
+            DATA_CMD => Message::Data { seq: u32_at(0) },
+            EXIT_CMD => Message::Exit,
+            SUMMARY_CMD => {
+                Message::Summary(RxSummary::parse(payload).map_err(|_| ErrorCode::Malformed)?)
+            }
+            HELLO_CMD => Message::Hello {
+                capabilities: u32_at(0),
+            },
+            ACK_CMD => Message::Ack { value: u32_at(0) },
+            ERROR_CMD => Message::Error {
+                command: payload[1],
+                code: payload[0].into(),
+            },
+            _ => return Err(ErrorCode::UnknownCommand),
+        };
+        Result::Ok((token, message))
+    }
+
+    /// The client a message belongs to
+    pub fn session(data: &[u8]) -> Option<u8> {
+        data.get(SESSION).copied()
+    }
+
+    /// ERROR reply for a message that did not parse
+    pub fn error_reply(data: &[u8], code: ErrorCode) -> (u8, Message) {
+        (
+            data.get(2).copied().unwrap_or_default(),
+            Message::Error {
+                command: data.first().copied().unwrap_or_default(),
+                code,
+            },
+        )
+    }
+}
+
+/// Client end of the control protocol, after a successful HELLO
+pub struct ControlClient<'a> {
+    pub rp1210: &'a Rp1210,
+    pub address: u8,
+    pub dest: u8,
+    pub pgn: u32,
+    /// from the server's HELLO ack
+    pub capabilities: u32,
+    token: AtomicU8,
+}
+
+impl<'a> ControlClient<'a> {
+    /// HELLO handshake. Fails when the server is missing, an older build or a different version.
+    pub fn connect(rp1210: &'a Rp1210, address: u8, dest: u8, pgn: u32) -> Result<Self> {
+        let mut client = ControlClient {
+            rp1210,
+            address,
+            dest,
+            pgn,
+            capabilities: 0,
+            token: AtomicU8::new(0),
+        };
+        let (_, capabilities) = client
+            .request(&Message::Hello {
+                capabilities: CAPABILITIES,
+            })
+            .map_err(|e| {
+                anyhow!(
+                    "No handshake with server {:02X} on {:04X} (protocol version {}): {}",
+                    dest,
+                    pgn,
+                    VERSION,
+                    e
+                )
+            })?;
+        client.capabilities = capabilities;
+        Ok(client)
+    }
+
+    /// Fail unless the server supports all of capabilities
+    pub fn require(&self, capabilities: u32) -> Result<()> {
+        if self.capabilities & capabilities != capabilities {
+            bail!(
+                "Server {:02X} capabilities {:08X} missing {:08X}",
+                self.dest,
+                self.capabilities,
+                capabilities & !self.capabilities
+            );
+        }
+        Ok(())
+    }
+
+    /// Everything the server sends this client on the control PGN. Subscribe before sending the request.
+    pub fn replies(&self) -> MqIter<J1939Packet> {
+        self.rp1210.bus.subscribe(self.session_filter())
+    }
+
+    /// Filter for the server's messages in this client's session.
+    /// Other versions are let through so wait() can report the mismatch.
+    pub fn session_filter(&self) -> impl Fn(&J1939Packet) -> bool + Send + Sync + 'static {
+        let (pgn, dest, address) = (self.pgn, self.dest, self.address);
+        move |p: &J1939Packet| {
+            p.pgn() == pgn
+                && p.source() == dest
+                && p.source() != address
+                && (Message::session(p.data()) == Some(address)
+                    || p.data().get(1) != Some(&VERSION))
+        }
+    }
+
+    /// Send a message without waiting for a reply. Returns the adapter echo.
+    pub fn send(&self, token: u8, message: &Message) -> Result<J1939Packet> {
+        self.rp1210.send(&J1939Packet::new_packet(
+            0x18,
+            self.pgn,
+            self.dest,
+            self.address,
+            &message.encode(self.address, token),
+        ))
+    }
+
+    /// Send a command and wait for its ACK. Returns the token and the ACK value.
+    pub fn request(&self, message: &Message) -> Result<(u8, u32)> {
+        let token = self.token.fetch_add(1, Relaxed).wrapping_add(1);
+        let mut replies = self.replies();
+        self.send(token, message)?;
+        match self.wait(&mut replies, token, ACK_CMD, REPLY_TIMEOUT)? {
+            Message::Ack { value } => Ok((token, value)),
+            m => bail!("Unexpected reply {:?}", m),
+        }
+    }
+
+    /// Wait for the reply with command and token. ERROR replies become errors.
+    pub fn wait(
+        &self,
+        replies: &mut MqIter<J1939Packet>,
+        token: u8,
+        command: u8,
+        timeout: Duration,
+    ) -> Result<Message> {
+        let until = Instant::now() + timeout;
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+            let p = replies
+                .next_timeout(left)
+                .ok_or_else(|| anyhow!("No reply from server {:02X}", self.dest))?;
+            match Message::parse(p.data()) {
+                Result::Ok((t, Message::Error { command, code })) if t == token => {
+                    bail!("Server error for command {}: {}", command, code)
+                }
+                Result::Ok((t, m)) if t == token && m.command() == command => return Ok(m),
+                Result::Ok(_) => {}
+                Err(ErrorCode::Version) => bail!(
+                    "Server speaks control protocol version {}, this build {}",
+                    p.data()[1],
+                    VERSION
+                ),
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::AdapterClock;
+    use crate::packet::PACKET_SIZE;
+
+    #[test]
+    fn round_trip() {
+        let params = TestParams {
+            count: 1000,
+            rate: Some(500.5),
+            ..Default::default()
+        };
+        let messages = [
+            Message::Ping { seq: 7 },
+            Message::Rx(params.clone()),
+            Message::Tx(TestParams::default()),
+            Message::Duplex(params.clone()),
+            Message::Tx(TestParams {
+                pattern: Pattern::Random,
+                length: MAX_LENGTH as u16,
+                seed: 0xDEADBEEF,
+                data_pgn: Some(0xFFF2),
+                idle: Duration::from_millis(30_500),
+                ..Default::default()
+            }),
+            Message::Data { seq: 0x01020304 },
+            Message::Exit,
+            Message::Summary(RxSummary {
+                expected: 10,
+                received: 9,
+                missing: vec![(3, 3)],
+                ..Default::default()
+            }),
+            Message::Hello {
+                capabilities: CAPABILITIES,
+            },
+            Message::Ack { value: CAP_PING },
+            Message::Error {
+                command: 42,
+                code: ErrorCode::UnknownCommand,
+            },
+        ];
+        for m in messages {
+            let data = m.encode(0xF8, 9);
+            assert!(data.len() >= 8);
+            assert_eq!(Some(0xF8), Message::session(&data));
+            assert_eq!(Result::Ok((9, m)), Message::parse(&data));
+        }
+        assert_eq!(
+            vec![DATA_CMD, VERSION, 0, 0xF8, 1, 2, 3, 4],
+            Message::Data { seq: 0x01020304 }.encode(0xF8, 0)
+        );
+        assert_eq!(27, Message::Rx(params).encode(0xF8, 1).len());
+    }
+
+    #[test]
+    fn payloads() {
+        let mut params = TestParams {
+            length: 10,
+            ..Default::default()
+        };
+        let data = params.payload(0xF8, 5);
+        assert_eq!(vec![DATA_CMD, VERSION, 0, 0xF8, 0, 0, 0, 5, 0, 0], data);
+        assert_eq!(Some((5, true)), params.check(0xF8, &data, 0));
+        let mut corrupt = data.clone();
+        corrupt[9] = 1;
+        assert_eq!(Some((5, false)), params.check(0xF8, &corrupt, 0));
+        // other control messages
+        assert_eq!(None, params.check(0xF8, &Message::Exit.encode(0xF8, 1), 0));
+
+        params.pattern = Pattern::Random;
+        params.data_pgn = Some(0xFFF2);
+        let data = params.payload(0xF8, 5);
+        assert_eq!(Some((5, true)), params.check(0xF8, &data, 3));
+        assert_eq!(Some((3, false)), params.check(0xF8, &[0; 10], 3));
+    }
+
+    #[test]
+    fn largest_transport() {
+        // transport --size 1785 --bam, built the way tx() sends it
+        let params = TestParams {
+            count: 1,
+            pattern: Pattern::Random,
+            length: MAX_LENGTH as u16,
+            seed: MAX_LENGTH as u32,
+            data_pgn: Some(0xFFF2),
+            ..Default::default()
+        };
+        assert_eq!(Result::Ok(()), params.validate(0xFFF1));
+        let data = params.payload(0xF8, 0);
+        let packet = J1939Packet::new_packet(0x18, params.pgn(0xFFF1), 0xF8, 0xF9, &data);
+        let mut buf = [0; PACKET_SIZE];
+        let len = packet.to_rp1210(&mut buf);
+
+        let read = [&[0, 0, 0, 0, 0], &buf[..len]].concat();
+        let received = J1939Packet::new_rp1210(&read, &mut AdapterClock::new(1000.0));
+        assert_eq!(Some((0, true)), params.check(0xF8, received.data(), 0));
+    }
+
+    #[test]
+    fn errors() {
+        // version 0 build
+        assert_eq!(
+            Err(ErrorCode::Version),
+            Message::parse(&[RX_CMD, 0, 0, 0, 0, 0, 0, 10])
+        );
+        // version 1 build
+        assert_eq!(
+            Err(ErrorCode::Version),
+            Message::parse(&[ACK_CMD, 1, 1, HELLO_CMD, 0, 0, 0, 7])
+        );
+        assert_eq!(
+            Err(ErrorCode::UnknownCommand),
+            Message::parse(&[42, VERSION, 3, 0, 0, 0, 0, 0])
+        );
+        assert_eq!(
+            Err(ErrorCode::Malformed),
+            Message::parse(&[PING_CMD, VERSION, 0])
+        );
+        // RX without the full parameters
+        assert_eq!(
+            Err(ErrorCode::Malformed),
+            Message::parse(&[RX_CMD, VERSION, 0, 0, 0, 0, 0, 10])
+        );
+        assert_eq!(
+            (
+                3,
+                Message::Error {
+                    command: 42,
+                    code: ErrorCode::UnknownCommand
+                }
+            ),
+            Message::error_reply(&[42, VERSION, 3, 0], ErrorCode::UnknownCommand)
+        );
+        assert_eq!(
+            Err(ErrorCode::Unsupported),
+            TestParams {
+                length: 4,
+                ..Default::default()
+            }
+            .validate(0xFFF1)
+        );
+        // patterns need their own PGN
+        let mut params = TestParams {
+            pattern: Pattern::Ones,
+            length: 0,
+            ..Default::default()
+        };
+        assert_eq!(Err(ErrorCode::Unsupported), params.validate(0xFFF1));
+        params.data_pgn = Some(0xFFF1);
+        assert_eq!(Err(ErrorCode::Unsupported), params.validate(0xFFF1));
+        params.data_pgn = Some(0xFFF2);
+        assert_eq!(Result::Ok(()), params.validate(0xFFF1));
+        params.length = MAX_LENGTH as u16 + 1;
+        assert_eq!(Err(ErrorCode::Unsupported), params.validate(0xFFF1));
+        // pattern from a newer build
+        let mut data = Message::Rx(TestParams::default()).encode(0xF8, 1);
+        data[HEADER + 8] = 42;
+        assert_eq!(Err(ErrorCode::Unsupported), Message::parse(&data));
+    }
+}
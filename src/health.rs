@@ -0,0 +1,315 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::{Duration, SystemTime};
+
+use crate::clock::format_system_time;
+use crate::command::*;
+use crate::rp1210_parsing::Protocol;
+
+/// RP1210 error codes the reader and watchdog care about
+pub const ERR_TX_QUEUE_FULL: i16 = 137;
+pub const ERR_RX_QUEUE_FULL: i16 = 139;
+pub const ERR_HARDWARE_NOT_RESPONDING: i16 = 142;
+pub const ERR_CLIENT_DISCONNECTED: i16 = 148;
+pub const ERR_BUS_OFF: i16 = 151;
+
+/// Read errors that mean the client connection is gone and has to be re-established
+pub fn is_fatal(code: i16) -> bool {
+    matches!(code, ERR_HARDWARE_NOT_RESPONDING | ERR_CLIENT_DISCONNECTED)
+}
+
+/// How the reader thread re-connects after a fatal read error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub enabled: bool,
+    /// delay before the first attempt, doubled after each failure
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// give up after this many attempts. None retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            enabled: true,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn disabled() -> ReconnectPolicy {
+        ReconnectPolicy {
+            enabled: false,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before attempt (1 based)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(16);
+        (self.initial_delay * (1 << doublings)).min(self.max_delay)
+    }
+
+    /// Is another attempt allowed after attempt failed
+    pub fn retry(&self, attempt: u32) -> bool {
+        self.enabled && self.max_attempts.is_none_or(|max| attempt < max)
+    }
+}
+
+/// RP1210_ReadDetailedVersion
+#[derive(Debug, Clone, Default)]
+pub struct Versions {
+    pub api: String,
+    pub dll: String,
+    pub firmware: String,
+}
+
+/// Error counts kept by the reader thread and send
+#[derive(Debug, Default)]
+pub struct Counters {
+    rx_overflows: AtomicU64,
+    tx_overflows: AtomicU64,
+    bus_off: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Counters {
+    /// Count an RP1210 error code
+    pub fn record(&self, code: i16) {
+        self.errors.fetch_add(1, Relaxed);
+        match code {
+            ERR_RX_QUEUE_FULL => self.rx_overflows.fetch_add(1, Relaxed),
+            ERR_TX_QUEUE_FULL => self.tx_overflows.fetch_add(1, Relaxed),
+            ERR_BUS_OFF => self.bus_off.fetch_add(1, Relaxed),
+            _ => 0,
+        };
+    }
+
+    pub fn health(&self, status: HardwareStatus) -> Health {
+        Health {
+            status,
+            rx_overflows: self.rx_overflows.load(Relaxed),
+            tx_overflows: self.tx_overflows.load(Relaxed),
+            bus_off: self.bus_off.load(Relaxed),
+            errors: self.errors.load(Relaxed),
+        }
+    }
+}
+
+/// Snapshot of the adapter state
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    pub status: HardwareStatus,
+    pub rx_overflows: u64,
+    pub tx_overflows: u64,
+    /// bus off errors reported by read
+    pub bus_off: u64,
+    /// all read and send errors
+    pub errors: u64,
+}
+
+impl Health {
+    /// status of the protocol this connection uses
+    pub fn protocol(&self, protocol: &Protocol) -> ProtocolStatus {
+        match protocol {
+            Protocol::J1708 => self.status.j1708,
+            Protocol::CAN => self.status.can,
+            Protocol::ISO15765 => self.status.iso15765,
+            _ => self.status.j1939,
+        }
+    }
+
+    /// Warnings for everything that got worse since prev
+    pub fn degradations(&self, prev: &Health, protocol: &Protocol) -> Vec<String> {
+        let mut rtn = Vec::new();
+        if prev.status.device_located && !self.status.device_located {
+            rtn.push("adapter no longer located".to_string());
+        }
+        let (was, is) = (prev.protocol(protocol), self.protocol(protocol));
+        if was.connected && !is.connected {
+            rtn.push(format!("{} disconnected", protocol));
+        }
+        if !was.bus_off && is.bus_off {
+            rtn.push(format!("{} bus off", protocol));
+        }
+        if !was.error_passive && is.error_passive {
+            rtn.push(format!("{} error passive", protocol));
+        }
+        let counts = [
+            ("RX queue overflows", prev.rx_overflows, self.rx_overflows),
+            ("TX queue overflows", prev.tx_overflows, self.tx_overflows),
+            ("bus off errors", prev.bus_off, self.bus_off),
+        ];
+        for (name, was, is) in counts {
+            if is > was {
+                rtn.push(format!("{} {} (total {})", is - was, name, is));
+            }
+        }
+        rtn
+    }
+}
+
+impl Display for ProtocolStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}{} clients: {}",
+            if self.connected {
+                "connected"
+            } else {
+                "not connected"
+            },
+            if self.activity { ", activity" } else { "" },
+            if self.bus_off { ", BUS OFF" } else { "" },
+            if self.error_passive {
+                ", ERROR PASSIVE"
+            } else {
+                ""
+            },
+            self.clients
+        )
+    }
+}
+
+/// Everything the info subcommand reports
+#[derive(Debug, Clone)]
+pub struct AdapterInfo {
+    pub versions: Versions,
+    pub protocol: Protocol,
+    pub health: Health,
+}
+
+impl Display for AdapterInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let h = &self.health;
+        writeln!(f, "API version:      {}", self.versions.api)?;
+        writeln!(f, "DLL version:      {}", self.versions.dll)?;
+        writeln!(f, "Firmware version: {}", self.versions.firmware)?;
+        writeln!(f, "Connected as:     {}", self.protocol)?;
+        writeln!(
+            f,
+            "Device:           {}{}",
+            if h.status.device_valid {
+                "valid"
+            } else {
+                "invalid"
+            },
+            if h.status.device_located {
+                ", located"
+            } else {
+                ", not located"
+            }
+        )?;
+        writeln!(f, "J1939:            {}", h.status.j1939)?;
+        writeln!(f, "J1708:            {}", h.status.j1708)?;
+        writeln!(f, "CAN:              {}", h.status.can)?;
+        writeln!(f, "ISO15765:         {}", h.status.iso15765)?;
+        writeln!(
+            f,
+            "Errors:           {} (RX overflows: {} TX overflows: {} bus off: {})",
+            h.errors, h.rx_overflows, h.tx_overflows, h.bus_off
+        )
+    }
+}
+
+/// Out of band adapter events, published on Rp1210::events
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub time: SystemTime,
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// the watchdog saw the adapter degrade
+    Warning(String),
+    /// fatal read error, the client connection is gone
+    Disconnected(String),
+    /// about to try client_connect again
+    Reconnecting(u32),
+    /// connected again, address claim and filters re-applied
+    Reconnected,
+}
+
+impl Event {
+    pub fn new(kind: EventKind) -> Event {
+        Event {
+            time: SystemTime::now(),
+            kind,
+        }
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", format_system_time(self.time))?;
+        match &self.kind {
+            EventKind::Warning(msg) => write!(f, "WARNING: {}", msg),
+            EventKind::Disconnected(msg) => write!(f, "DISCONNECTED: {}", msg),
+            EventKind::Reconnecting(attempt) => write!(f, "RECONNECTING: attempt {}", attempt),
+            EventKind::Reconnected => write!(f, "RECONNECTED"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn degradations() {
+        let counters = Counters::default();
+        let mut status = HardwareStatus {
+            device_valid: true,
+            device_located: true,
+            ..Default::default()
+        };
+        status.j1939.connected = true;
+        let before = counters.health(status.clone());
+        assert!(before.degradations(&before, &Protocol::J1939).is_empty());
+
+        counters.record(ERR_RX_QUEUE_FULL);
+        counters.record(ERR_RX_QUEUE_FULL);
+        counters.record(ERR_HARDWARE_NOT_RESPONDING);
+        status.j1939.bus_off = true;
+        let after = counters.health(status);
+        assert_eq!(3, after.errors);
+        assert_eq!(
+            vec!["J1939 bus off", "2 RX queue overflows (total 2)"],
+            after.degradations(&before, &Protocol::J1939)
+        );
+        // CAN status is unchanged
+        assert_eq!(
+            vec!["2 RX queue overflows (total 2)"],
+            after.degradations(&before, &Protocol::CAN)
+        );
+        // no repeats
+        assert!(after.degradations(&after, &Protocol::J1939).is_empty());
+    }
+
+    #[test]
+    fn reconnect_policy() {
+        assert!(is_fatal(ERR_CLIENT_DISCONNECTED));
+        assert!(is_fatal(ERR_HARDWARE_NOT_RESPONDING));
+        assert!(!is_fatal(ERR_RX_QUEUE_FULL));
+        assert!(!is_fatal(ERR_BUS_OFF));
+
+        let policy = ReconnectPolicy::default();
+        let secs: Vec<u64> = (1..=7).map(|a| policy.delay(a).as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 8, 16, 30, 30], secs);
+        assert_eq!(policy.max_delay, policy.delay(u32::MAX));
+        assert!(policy.retry(1_000_000));
+
+        let limited = ReconnectPolicy {
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+        assert!(limited.retry(2));
+        assert!(!limited.retry(3));
+        assert!(!ReconnectPolicy::disabled().retry(0));
+    }
+}
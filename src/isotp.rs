@@ -0,0 +1,429 @@
+use anyhow::*;
+use std::time::Duration;
+
+use crate::packet::*;
+use crate::rp1210::*;
+
+/// ISO 15765-2 over J1939 using 29 bit normal fixed addressing: 18DA<target><source>
+pub const PHYSICAL_PGN: u32 = 0xDA00;
+/// functional (broadcast) requests: 18DB33<source>
+pub const FUNCTIONAL_PGN: u32 = 0xDB00;
+pub const FUNCTIONAL_ADDRESS: u8 = 0x33;
+
+/// largest payload with a 12 bit first frame length
+pub const MAX_LEN: usize = 4095;
+
+/// fill for unused bytes
+const PADDING: u8 = 0xCC;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowStatus {
+    ContinueToSend,
+    Wait,
+    Overflow,
+}
+
+/// ISO-TP protocol control information
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Single(Vec<u8>),
+    First {
+        len: usize,
+        data: Vec<u8>,
+    },
+    Consecutive {
+        sn: u8,
+        data: Vec<u8>,
+    },
+    FlowControl {
+        status: FlowStatus,
+        block_size: u8,
+        st_min: u8,
+    },
+}
+
+impl Frame {
+    pub fn parse(data: &[u8]) -> Result<Frame> {
+        if data.is_empty() {
+            bail!("Empty ISO-TP frame");
+        }
+        let pci = data[0] & 0x0F;
+        match data[0] >> 4 {
+            0 => {
+                let len = pci as usize;
+                if len == 0 || len + 1 > data.len() {
+                    bail!("Invalid single frame length {}", len);
+                }
+                Ok(Frame::Single(data[1..1 + len].to_vec()))
+            }
+            1 => {
+                if data.len() < 2 {
+                    bail!("Short first frame");
+                }
+                let len = ((pci as usize) << 8) | data[1] as usize;
+                Ok(Frame::First {
+                    len,
+                    data: data[2..].to_vec(),
+                })
+            }
+            2 => Ok(Frame::Consecutive {
+                sn: pci,
+                data: data[1..].to_vec(),
+            }),
+            3 => {
+                if data.len() < 3 {
+                    bail!("Short flow control frame");
+                }
+                let status = match pci {
+                    0 => FlowStatus::ContinueToSend,
+                    1 => FlowStatus::Wait,
+                    2 => FlowStatus::Overflow,
+                    s => bail!("Invalid flow status {}", s),
+                };
+                Ok(Frame::FlowControl {
+                    status,
+                    block_size: data[1],
+                    st_min: data[2],
+                })
+            }
+            t => bail!("Invalid ISO-TP frame type {}", t),
+        }
+    }
+
+    /// encode as a padded 8 byte CAN payload
+    pub fn encode(&self) -> [u8; 8] {
+        let mut buf = [PADDING; 8];
+        match self {
+            Frame::Single(data) => {
+                buf[0] = data.len() as u8;
+                buf[1..1 + data.len()].copy_from_slice(data);
+            }
+            Frame::First { len, data } => {
+                buf[0] = 0x10 | (*len >> 8) as u8;
+                buf[1] = *len as u8;
+                buf[2..2 + data.len()].copy_from_slice(data);
+            }
+            Frame::Consecutive { sn, data } => {
+                buf[0] = 0x20 | (sn & 0x0F);
+                buf[1..1 + data.len()].copy_from_slice(data);
+            }
+            Frame::FlowControl {
+                status,
+                block_size,
+                st_min,
+            } => {
+                buf[0] = 0x30
+                    | match status {
+                        FlowStatus::ContinueToSend => 0,
+                        FlowStatus::Wait => 1,
+                        FlowStatus::Overflow => 2,
+                    };
+                buf[1] = *block_size;
+                buf[2] = *st_min;
+            }
+        }
+        buf
+    }
+}
+
+/// Split a payload into single, or first and consecutive, frames
+pub fn segment(payload: &[u8]) -> Result<Vec<Frame>> {
+    if payload.is_empty() || payload.len() > MAX_LEN {
+        bail!("Invalid ISO-TP payload length {}", payload.len());
+    }
+    if payload.len() <= 7 {
+        return Ok(vec![Frame::Single(payload.to_vec())]);
+    }
+    let mut frames = vec![Frame::First {
+        len: payload.len(),
+        data: payload[..6].to_vec(),
+    }];
+    frames.extend(
+        payload[6..]
+            .chunks(7)
+            .enumerate()
+            .map(|(i, c)| Frame::Consecutive {
+                sn: ((i + 1) & 0x0F) as u8,
+                data: c.to_vec(),
+            }),
+    );
+    Ok(frames)
+}
+
+/// separation time from the STmin byte
+pub fn st_min_duration(st_min: u8) -> Duration {
+    match st_min {
+        0x00..=0x7F => Duration::from_millis(st_min as u64),
+        0xF1..=0xF9 => Duration::from_micros((st_min - 0xF0) as u64 * 100),
+        // reserved values are treated as the maximum
+        _ => Duration::from_millis(0x7F),
+    }
+}
+
+/// Reassembles a segmented message
+#[derive(Debug, Default)]
+pub struct Reassembly {
+    len: usize,
+    next_sn: u8,
+    data: Vec<u8>,
+}
+
+impl Reassembly {
+    /// Start with a first frame
+    pub fn new(len: usize, data: &[u8]) -> Reassembly {
+        let mut data = data.to_vec();
+        data.truncate(len);
+        Reassembly {
+            len,
+            next_sn: 1,
+            data,
+        }
+    }
+    /// Add a consecutive frame. Returns the payload once complete.
+    pub fn add(&mut self, sn: u8, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        if sn != self.next_sn {
+            bail!(
+                "ISO-TP sequence error. expected {} received {}",
+                self.next_sn,
+                sn
+            );
+        }
+        self.next_sn = (self.next_sn + 1) & 0x0F;
+        let remaining = self.len - self.data.len();
+        self.data
+            .extend_from_slice(&data[..remaining.min(data.len())]);
+        Ok(if self.data.len() == self.len {
+            Some(std::mem::take(&mut self.data))
+        } else {
+            None
+        })
+    }
+}
+
+/// ISO-TP connection to one target address
+pub struct IsoTp<'a> {
+    rp1210: &'a Rp1210,
+    pub source: u8,
+    pub target: u8,
+    /// block size sent in our flow control frames. 0 for no further flow control.
+    pub block_size: u8,
+    /// STmin sent in our flow control frames
+    pub st_min: u8,
+    /// N_Bs/N_Cr: how long to wait for the next frame from the target
+    pub timeout: Duration,
+}
+
+impl<'a> IsoTp<'a> {
+    pub fn new(rp1210: &'a Rp1210, source: u8, target: u8) -> IsoTp<'a> {
+        IsoTp {
+            rp1210,
+            source,
+            target,
+            block_size: 0,
+            st_min: 0,
+            timeout: Duration::from_millis(1000),
+        }
+    }
+
+    fn send_frame(&self, frame: &Frame) -> Result<J1939Packet> {
+        self.rp1210.send(&J1939Packet::new_packet(
+            0x18,
+            PHYSICAL_PGN,
+            self.target,
+            self.source,
+            &frame.encode(),
+        ))
+    }
+
+    /// frames from the target addressed to us. Ends after idle without a frame.
+    fn subscribe(&self, idle: Duration) -> impl Iterator<Item = J1939Packet> {
+        self.rp1210.bus.iter_filtered_idle(
+            idle,
+            J1939Filter::new()
+                .pgn(PHYSICAL_PGN | self.source as u32)
+                .source(self.target),
+        )
+    }
+
+    /// next frame, or an error when the subscription times out
+    fn next_frame(&self, rx: &mut impl Iterator<Item = J1939Packet>) -> Result<Frame> {
+        rx.next()
+            .ok_or_else(|| anyhow!("ISO-TP timeout from {:02X}", self.target))
+            .and_then(|p| Frame::parse(p.data()))
+    }
+
+    /// Send a functional (broadcast) single frame
+    pub fn send_functional(&self, payload: &[u8]) -> Result<()> {
+        if payload.len() > 7 {
+            bail!("Functional requests must fit in a single frame");
+        }
+        self.rp1210.send(&J1939Packet::new_packet(
+            0x18,
+            FUNCTIONAL_PGN,
+            FUNCTIONAL_ADDRESS,
+            self.source,
+            &Frame::Single(payload.to_vec()).encode(),
+        ))?;
+        Ok(())
+    }
+
+    /// Send a payload, honoring the target's flow control
+    #[allow(dead_code)]
+    pub fn send(&self, payload: &[u8]) -> Result<()> {
+        let mut rx = self.subscribe(self.timeout);
+        self.send_with(payload, &mut rx)
+    }
+
+    fn send_with(&self, payload: &[u8], rx: &mut impl Iterator<Item = J1939Packet>) -> Result<()> {
+        let mut frames = segment(payload)?.into_iter();
+        let first = frames.next().unwrap();
+        self.send_frame(&first)?;
+        let mut frames = frames.peekable();
+        while frames.peek().is_some() {
+            let (block_size, st_min) = match self.next_frame(rx)? {
+                Frame::FlowControl {
+                    status: FlowStatus::ContinueToSend,
+                    block_size,
+                    st_min,
+                } => (block_size, st_min_duration(st_min)),
+                Frame::FlowControl {
+                    status: FlowStatus::Wait,
+                    ..
+                } => continue,
+                Frame::FlowControl {
+                    status: FlowStatus::Overflow,
+                    ..
+                } => bail!("ISO-TP overflow from {:02X}", self.target),
+                f => bail!("Expected flow control, received {:?}", f),
+            };
+            let mut sent = 0;
+            while let Some(frame) = frames.next() {
+                self.send_frame(&frame)?;
+                sent += 1;
+                if frames.peek().is_none() || (block_size != 0 && sent == block_size) {
+                    break;
+                }
+                std::thread::sleep(st_min);
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive one payload, sending flow control as needed
+    pub fn receive(&self, rx: &mut impl Iterator<Item = J1939Packet>) -> Result<Vec<u8>> {
+        let (len, data) = match self.next_frame(rx)? {
+            Frame::Single(data) => return Ok(data),
+            Frame::First { len, data } => (len, data),
+            f => bail!("Expected single or first frame, received {:?}", f),
+        };
+        let mut reassembly = Reassembly::new(len, &data);
+        let flow_control = Frame::FlowControl {
+            status: FlowStatus::ContinueToSend,
+            block_size: self.block_size,
+            st_min: self.st_min,
+        };
+        self.send_frame(&flow_control)?;
+        let mut received = 0;
+        loop {
+            match self.next_frame(rx)? {
+                Frame::Consecutive { sn, data } => {
+                    if let Some(payload) = reassembly.add(sn, &data)? {
+                        return Ok(payload);
+                    }
+                    received += 1;
+                    if self.block_size != 0 && received == self.block_size {
+                        received = 0;
+                        self.send_frame(&flow_control)?;
+                    }
+                }
+                f => bail!("Expected consecutive frame, received {:?}", f),
+            }
+        }
+    }
+
+    /// Send a request and return a subscription positioned before the response.
+    /// The subscription ends after response_timeout without a frame.
+    pub fn request(
+        &self,
+        payload: &[u8],
+        response_timeout: Duration,
+    ) -> Result<impl Iterator<Item = J1939Packet>> {
+        let mut rx = self.subscribe(response_timeout.max(self.timeout));
+        self.send_with(payload, &mut rx)?;
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single() {
+        let frames = segment(&[0x22, 0xF1, 0x90]).unwrap();
+        assert_eq!(1, frames.len());
+        let buf = frames[0].encode();
+        assert_eq!([0x03, 0x22, 0xF1, 0x90, 0xCC, 0xCC, 0xCC, 0xCC], buf);
+        assert_eq!(frames[0], Frame::parse(&buf).unwrap());
+    }
+
+    #[test]
+    fn multi() {
+        let payload: Vec<u8> = (0..20).collect();
+        let frames = segment(&payload).unwrap();
+        // 6 + 7 + 7
+        assert_eq!(3, frames.len());
+        assert_eq!([0x10, 20, 0, 1, 2, 3, 4, 5], frames[0].encode());
+        assert_eq!([0x21, 6, 7, 8, 9, 10, 11, 12], frames[1].encode());
+        assert_eq!([0x22, 13, 14, 15, 16, 17, 18, 19], frames[2].encode());
+
+        let (len, data) = match Frame::parse(&frames[0].encode()).unwrap() {
+            Frame::First { len, data } => (len, data),
+            f => panic!("{:?}", f),
+        };
+        let mut r = Reassembly::new(len, &data);
+        assert_eq!(None, r.add(1, &frames[1].encode()[1..]).unwrap());
+        assert_eq!(Some(payload), r.add(2, &frames[2].encode()[1..]).unwrap());
+    }
+
+    #[test]
+    fn sequence_error() {
+        let mut r = Reassembly::new(20, &[0; 6]);
+        assert!(r.add(2, &[0; 7]).is_err());
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let payload = vec![0x55; 6 + 7 * 16];
+        let frames = segment(&payload).unwrap();
+        assert_eq!(
+            Frame::Consecutive {
+                sn: 0,
+                data: vec![0x55; 7]
+            },
+            frames[16]
+        );
+    }
+
+    #[test]
+    fn flow_control() {
+        let fc = Frame::FlowControl {
+            status: FlowStatus::Wait,
+            block_size: 8,
+            st_min: 0xF5,
+        };
+        assert_eq!([0x31, 8, 0xF5, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC], fc.encode());
+        assert_eq!(fc, Frame::parse(&fc.encode()).unwrap());
+        assert_eq!(Duration::from_micros(500), st_min_duration(0xF5));
+        assert_eq!(Duration::from_millis(20), st_min_duration(20));
+        assert_eq!(Duration::from_millis(127), st_min_duration(0x80));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(segment(&[]).is_err());
+        assert!(segment(&[0; MAX_LEN + 1]).is_err());
+        assert!(Frame::parse(&[0x00, 1, 2]).is_err());
+        assert!(Frame::parse(&[0x40]).is_err());
+    }
+}
@@ -0,0 +1,459 @@
+use anyhow::*;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use crate::j1708::*;
+use crate::packet::{as_hex, Origin};
+use crate::rp1210::*;
+
+/// PID 255 prefixes a page 2 PID (256-511)
+const PAGE_2: u8 = 255;
+/// PID 254, the rest of the message is proprietary
+const DATA_LINK_ESCAPE: u8 = 254;
+
+pub const MULTISECTION_PID: u16 = 192;
+pub const CONNECTION_MANAGEMENT_PID: u16 = 197;
+pub const CONNECTION_DATA_PID: u16 = 198;
+
+const RTS: u8 = 1;
+const CTS: u8 = 2;
+const EOM: u8 = 3;
+const ABORT: u8 = 255;
+/// data bytes in one PID 198 segment, keeping the message within 21 bytes
+const SEGMENT_LEN: usize = 15;
+
+/// One J1587 parameter from a J1708 message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Parameter {
+    pub pid: u16,
+    /// parameter data, without the length byte of variable length PIDs
+    pub data: Vec<u8>,
+}
+
+impl Display for Parameter {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match decode(self) {
+            Some(value) => write!(f, "PID {} {}: {}", self.pid, name(self.pid), value),
+            None => write!(
+                f,
+                "PID {} [{}] {}",
+                self.pid,
+                self.data.len(),
+                as_hex(&self.data)
+            ),
+        }
+    }
+}
+
+/// Split J1708 message data into J1587 parameters.
+/// The PID determines the length: 0-127 one byte, 128-191 two bytes, 192-253 a length byte then data.
+pub fn parameters(data: &[u8]) -> Result<Vec<Parameter>> {
+    let mut rtn = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (page, pid_byte) = if rest[0] == PAGE_2 {
+            if rest.len() < 2 {
+                bail!("Page 2 PID missing: {}", as_hex(data));
+            }
+            rest = &rest[1..];
+            (256, rest[0])
+        } else {
+            (0, rest[0])
+        };
+        let pid = page + pid_byte as u16;
+        rest = &rest[1..];
+        let (start, len) = match pid_byte {
+            0..=127 => (0, 1),
+            128..=191 => (0, 2),
+            DATA_LINK_ESCAPE => (0, rest.len()),
+            _ => (1, *rest.first().unwrap_or(&0) as usize),
+        };
+        if rest.len() < start + len {
+            bail!("PID {} truncated: {}", pid, as_hex(data));
+        }
+        rtn.push(Parameter {
+            pid,
+            data: rest[start..start + len].to_vec(),
+        });
+        rest = &rest[start + len..];
+    }
+    Ok(rtn)
+}
+
+/// Encode one parameter, adding the page and length bytes as needed
+pub fn encode(parameter: &Parameter) -> Vec<u8> {
+    let mut rtn = Vec::with_capacity(parameter.data.len() + 3);
+    if parameter.pid > 255 {
+        rtn.push(PAGE_2);
+    }
+    let pid_byte = parameter.pid as u8;
+    rtn.push(pid_byte);
+    if (192..DATA_LINK_ESCAPE).contains(&pid_byte) {
+        rtn.push(parameter.data.len() as u8);
+    }
+    rtn.extend_from_slice(&parameter.data);
+    rtn
+}
+
+pub fn name(pid: u16) -> &'static str {
+    match pid {
+        84 => "Road Speed",
+        91 => "Percent Accelerator Pedal Position",
+        92 => "Percent Engine Load",
+        96 => "Fuel Level",
+        100 => "Engine Oil Pressure",
+        102 => "Boost Pressure",
+        110 => "Engine Coolant Temperature",
+        168 => "Battery Potential",
+        190 => "Engine Speed",
+        192 => "Multisection Parameter",
+        194 => "Diagnostic Code Table",
+        197 => "Connection Management",
+        198 => "Connection Mode Data Transfer",
+        234 => "Software Identification",
+        237 => "Vehicle Identification Number",
+        243 => "Component Identification",
+        245 => "Total Vehicle Distance",
+        247 => "Total Engine Hours",
+        _ => "",
+    }
+}
+
+/// Value of the common PIDs in engineering units
+pub fn decode(parameter: &Parameter) -> Option<String> {
+    let d = &parameter.data;
+    let u16_le = || u16::from_le_bytes([d[0], d[1]]) as f64;
+    let u32_le = || u32::from_le_bytes(d[..4].try_into().unwrap()) as f64;
+    let ascii = || String::from_utf8_lossy(d).trim_end_matches('*').to_string();
+    Some(match (parameter.pid, d.len()) {
+        (84, 1) => format!("{:.1} km/h", d[0] as f64 * 0.805),
+        (91, 1) => format!("{:.1} %", d[0] as f64 * 0.4),
+        (92, 1) | (96, 1) => format!("{:.1} %", d[0] as f64 * 0.5),
+        (100, 1) => format!("{:.1} kPa", d[0] as f64 * 3.447),
+        (102, 1) => format!("{:.1} kPa", d[0] as f64 * 0.862),
+        (110, 1) => format!("{} °F", d[0]),
+        (168, 2) => format!("{:.2} V", u16_le() * 0.05),
+        (190, 2) => format!("{:.2} rpm", u16_le() * 0.25),
+        (234, _) | (237, _) | (243, _) => ascii(),
+        (245, 4) => format!("{:.1} km", u32_le() * 0.161),
+        (247, 4) => format!("{:.2} h", u32_le() * 0.05),
+        _ => return None,
+    })
+}
+
+/// J1587 transport messages, PID 197 and 198
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    RequestToSend {
+        dest: u8,
+        segments: u8,
+        len: u16,
+    },
+    ClearToSend {
+        dest: u8,
+        count: u8,
+        next: u8,
+    },
+    EndOfMessage {
+        dest: u8,
+    },
+    Abort {
+        dest: u8,
+    },
+    Data {
+        dest: u8,
+        segment: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl Transport {
+    pub fn parse(parameter: &Parameter) -> Result<Transport> {
+        let d = &parameter.data;
+        Ok(match (parameter.pid, d.as_slice()) {
+            (CONNECTION_MANAGEMENT_PID, [dest, RTS, segments, lo, hi, ..]) => {
+                Transport::RequestToSend {
+                    dest: *dest,
+                    segments: *segments,
+                    len: u16::from_le_bytes([*lo, *hi]),
+                }
+            }
+            (CONNECTION_MANAGEMENT_PID, [dest, CTS, count, next, ..]) => Transport::ClearToSend {
+                dest: *dest,
+                count: *count,
+                next: *next,
+            },
+            (CONNECTION_MANAGEMENT_PID, [dest, EOM, ..]) => Transport::EndOfMessage { dest: *dest },
+            (CONNECTION_MANAGEMENT_PID, [dest, ABORT, ..]) => Transport::Abort { dest: *dest },
+            (CONNECTION_DATA_PID, [dest, segment, data @ ..]) => Transport::Data {
+                dest: *dest,
+                segment: *segment,
+                data: data.to_vec(),
+            },
+            _ => bail!("Invalid transport message: {}", parameter),
+        })
+    }
+
+    pub fn to_parameter(&self) -> Parameter {
+        let (pid, data) = match self {
+            Transport::RequestToSend {
+                dest,
+                segments,
+                len,
+            } => {
+                let len = len.to_le_bytes();
+                (
+                    CONNECTION_MANAGEMENT_PID,
+                    vec![*dest, RTS, *segments, len[0], len[1]],
+                )
+            }
+            Transport::ClearToSend { dest, count, next } => {
+                (CONNECTION_MANAGEMENT_PID, vec![*dest, CTS, *count, *next])
+            }
+            Transport::EndOfMessage { dest } => (CONNECTION_MANAGEMENT_PID, vec![*dest, EOM]),
+            Transport::Abort { dest } => (CONNECTION_MANAGEMENT_PID, vec![*dest, ABORT]),
+            Transport::Data {
+                dest,
+                segment,
+                data,
+            } => (
+                CONNECTION_DATA_PID,
+                [&[*dest, *segment], data.as_slice()].concat(),
+            ),
+        };
+        Parameter { pid, data }
+    }
+}
+
+/// Split a payload into PID 198 segments, numbered from 1
+pub fn segment(dest: u8, payload: &[u8]) -> Result<Vec<Transport>> {
+    if payload.len() > SEGMENT_LEN * 255 {
+        bail!("J1587 transport payload too long: {}", payload.len());
+    }
+    Ok(payload
+        .chunks(SEGMENT_LEN)
+        .enumerate()
+        .map(|(i, data)| Transport::Data {
+            dest,
+            segment: i as u8 + 1,
+            data: data.to_vec(),
+        })
+        .collect())
+}
+
+/// Passively reassembles PID 192 multisection parameters and PID 197/198 transport sessions
+/// from every MID on the bus, i.e. for logging.
+#[derive(Default)]
+pub struct Reassembler {
+    /// source MID -> sections received so far
+    multisection: HashMap<u8, Vec<u8>>,
+    /// (source MID, dest MID) -> announced length, data received so far
+    sessions: HashMap<(u8, u8), (usize, Vec<u8>)>,
+}
+
+impl Reassembler {
+    /// Add one parameter from a message sent by mid.
+    /// Returns the complete parameter once the last section or segment arrives.
+    pub fn add(&mut self, mid: u8, parameter: &Parameter) -> Result<Option<Parameter>> {
+        match parameter.pid {
+            MULTISECTION_PID => {
+                // section byte: current section in the high nibble, last section in the low nibble
+                let (section, data) = parameter
+                    .data
+                    .split_first()
+                    .ok_or_else(|| anyhow!("Empty multisection parameter"))?;
+                let (current, last) = (section >> 4, section & 0x0F);
+                if current == 0 {
+                    self.multisection.insert(mid, Vec::new());
+                }
+                let buf = self
+                    .multisection
+                    .get_mut(&mid)
+                    .ok_or_else(|| anyhow!("MID {} section {} without section 0", mid, current))?;
+                buf.extend_from_slice(data);
+                if current < last {
+                    return Ok(None);
+                }
+                // section 0 starts with the PID of the contained parameter
+                let buf = self.multisection.remove(&mid).unwrap();
+                let (pid, data) = buf
+                    .split_first()
+                    .ok_or_else(|| anyhow!("Empty multisection parameter"))?;
+                Ok(Some(Parameter {
+                    pid: *pid as u16,
+                    data: data.to_vec(),
+                }))
+            }
+            CONNECTION_MANAGEMENT_PID | CONNECTION_DATA_PID => match Transport::parse(parameter)? {
+                Transport::RequestToSend { dest, len, .. } => {
+                    self.sessions.insert(
+                        (mid, dest),
+                        (len as usize, Vec::with_capacity(len as usize)),
+                    );
+                    Ok(None)
+                }
+                Transport::Abort { dest } => {
+                    // either side may abort
+                    self.sessions.remove(&(mid, dest));
+                    self.sessions.remove(&(dest, mid));
+                    Ok(None)
+                }
+                Transport::Data {
+                    dest,
+                    segment,
+                    data,
+                } => {
+                    let Some((len, buf)) = self.sessions.get_mut(&(mid, dest)) else {
+                        return Ok(None);
+                    };
+                    // retransmitted segments overwrite
+                    let offset = (segment as usize).saturating_sub(1) * SEGMENT_LEN;
+                    if offset > buf.len() {
+                        bail!("MID {} to {}: segment {} out of order", mid, dest, segment);
+                    }
+                    buf.truncate(offset);
+                    buf.extend_from_slice(&data);
+                    if buf.len() < *len {
+                        return Ok(None);
+                    }
+                    let (_, buf) = self.sessions.remove(&(mid, dest)).unwrap();
+                    Ok(Some(Parameter {
+                        pid: CONNECTION_DATA_PID,
+                        data: buf,
+                    }))
+                }
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Send a payload to dest with the J1587 connection mode transport.
+/// RTS, then the segments requested by each CTS, until the receiver sends EOM.
+pub fn send_transport(rp1210: &Rp1210, mid: u8, dest: u8, payload: &[u8]) -> Result<()> {
+    let segments = segment(dest, payload)?;
+    let from_dest = move |m: &J1708Message| m.mid() == dest && m.origin() == Origin::Bus;
+    let mut rx = rp1210
+        .j1708_bus
+        .iter_filtered_idle(Duration::from_secs(1), from_dest);
+    let send =
+        |t: &Transport| rp1210.send_j1708(&J1708Message::new(8, mid, &encode(&t.to_parameter())));
+    send(&Transport::RequestToSend {
+        dest,
+        segments: segments.len() as u8,
+        len: payload.len() as u16,
+    })?;
+    loop {
+        let reply = rx
+            .find_map(|m| {
+                parameters(m.data())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|p| p.pid == CONNECTION_MANAGEMENT_PID)
+                    .filter_map(|p| Transport::parse(&p).ok())
+                    .find(|t| match t {
+                        Transport::ClearToSend { dest, .. }
+                        | Transport::EndOfMessage { dest }
+                        | Transport::Abort { dest } => *dest == mid,
+                        _ => false,
+                    })
+            })
+            .ok_or_else(|| anyhow!("Timeout waiting for CTS from MID {}", dest))?;
+        match reply {
+            Transport::ClearToSend { count, next, .. } => {
+                let start = (next as usize).max(1) - 1;
+                for s in segments.iter().skip(start).take(count as usize) {
+                    send(s)?;
+                }
+            }
+            Transport::EndOfMessage { .. } => return Ok(()),
+            _ => bail!("MID {} aborted transport", dest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_parameters() {
+        // road speed, engine speed, VIN, page 2 PID 300
+        let data = [
+            84, 100, 190, 0x70, 0x17, 237, 3, b'A', b'B', b'C', 255, 44, 7,
+        ];
+        let p = parameters(&data).unwrap();
+        assert_eq!(4, p.len());
+        assert_eq!("PID 84 Road Speed: 80.5 km/h", p[0].to_string());
+        assert_eq!("PID 190 Engine Speed: 1500.00 rpm", p[1].to_string());
+        assert_eq!("ABC", decode(&p[2]).unwrap());
+        assert_eq!(
+            Parameter {
+                pid: 300,
+                data: vec![7]
+            },
+            p[3]
+        );
+        let encoded: Vec<u8> = p.iter().flat_map(encode).collect();
+        assert_eq!(&data, encoded.as_slice());
+
+        assert!(parameters(&[190, 1]).is_err());
+        assert!(parameters(&[237, 5, 1]).is_err());
+    }
+
+    #[test]
+    fn multisection() {
+        let mut r = Reassembler::default();
+        let section = |s: u8, data: &[u8]| Parameter {
+            pid: MULTISECTION_PID,
+            data: [&[s], data].concat(),
+        };
+        assert_eq!(
+            None,
+            r.add(128, &section(0x01, &[237, b'1', b'2'])).unwrap()
+        );
+        assert_eq!(
+            Some(Parameter {
+                pid: 237,
+                data: b"1234".to_vec()
+            }),
+            r.add(128, &section(0x11, b"34")).unwrap()
+        );
+        assert!(r.add(128, &section(0x11, b"34")).is_err());
+    }
+
+    #[test]
+    fn transport() {
+        let payload: Vec<u8> = (0..40).collect();
+        let segments = segment(140, &payload).unwrap();
+        assert_eq!(3, segments.len());
+
+        let mut r = Reassembler::default();
+        let rts = Transport::RequestToSend {
+            dest: 140,
+            segments: 3,
+            len: 40,
+        };
+        let p = rts.to_parameter();
+        assert_eq!(rts, Transport::parse(&p).unwrap());
+        assert_eq!(None, r.add(172, &p).unwrap());
+        let cts = Transport::ClearToSend {
+            dest: 172,
+            count: 3,
+            next: 1,
+        };
+        assert_eq!(None, r.add(140, &cts.to_parameter()).unwrap());
+        // data for another session is ignored
+        assert_eq!(None, r.add(128, &segments[0].to_parameter()).unwrap());
+        assert_eq!(None, r.add(172, &segments[0].to_parameter()).unwrap());
+        assert_eq!(None, r.add(172, &segments[1].to_parameter()).unwrap());
+        assert_eq!(
+            Some(payload),
+            r.add(172, &segments[2].to_parameter())
+                .unwrap()
+                .map(|p| p.data)
+        );
+    }
+}
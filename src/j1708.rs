@@ -0,0 +1,126 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::clock::*;
+use crate::packet::{as_hex, Origin};
+
+/// Data bytes in one message: 21 on the wire, less the MID and checksum
+pub const MAX_DATA: usize = 19;
+
+/// J1708 message: MID followed by data. The checksum is not stored: in RP1210's default converted mode
+/// the DLL validates and strips it from received messages and appends it to sent ones.
+#[derive(Default, Debug, Clone)]
+pub struct J1708Message {
+    /// microseconds. Adapter clock for received and echoed messages, host clock for local messages.
+    time: u64,
+    /// host wall clock, microseconds since UNIX_EPOCH
+    wall: u64,
+    /// transmit priority 1 (highest) to 8. Only used when sending.
+    priority: u8,
+    mid: u8,
+    origin: Origin,
+    data: Vec<u8>,
+}
+
+impl Display for J1708Message {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:12.4} MID {:3} [{}]",
+            self.time(),
+            self.mid,
+            self.data.len()
+        )?;
+        if !self.data.is_empty() {
+            write!(f, " {}", as_hex(&self.data))?;
+        }
+        if self.echo() {
+            write!(f, " (TX)")?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+#[allow(dead_code)]
+impl J1708Message {
+    /// Build a message to send
+    pub fn new(priority: u8, mid: u8, data: &[u8]) -> J1708Message {
+        J1708Message {
+            time: host_time(),
+            wall: micros_since_epoch(SystemTime::now()),
+            priority: priority.clamp(1, 8),
+            mid,
+            origin: Origin::Local,
+            data: data.to_vec(),
+        }
+    }
+
+    /// Parse an RP1210 J1708 read buffer:
+    /// timestamp[4] echo[1] MID[1] data. The DLL validates and strips the checksum.
+    pub fn new_rp1210(data: &[u8], clock: &mut AdapterClock) -> J1708Message {
+        let time = clock.extend(u32::from_be_bytes(data[0..4].try_into().unwrap()));
+        J1708Message {
+            time,
+            wall: micros_since_epoch(clock.to_system_time(time).unwrap_or_else(SystemTime::now)),
+            priority: 0,
+            mid: data[5],
+            origin: if data[4] != 0 {
+                Origin::Echo
+            } else {
+                Origin::Bus
+            },
+            data: data[6..].to_vec(),
+        }
+    }
+
+    /// Write the RP1210 J1708 send buffer: priority[1] MID[1] data.
+    /// The DLL appends the checksum. Returns the number of bytes written.
+    pub fn to_rp1210(&self, buf: &mut [u8]) -> usize {
+        buf[0] = self.priority;
+        buf[1] = self.mid;
+        buf[2..2 + self.data.len()].copy_from_slice(&self.data);
+        2 + self.data.len()
+    }
+
+    /// milliseconds
+    pub fn time(&self) -> f64 {
+        self.time as f64 * 0.001
+    }
+    pub fn wall_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.wall)
+    }
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+    pub fn mid(&self) -> u8 {
+        self.mid
+    }
+    pub fn origin(&self) -> Origin {
+        self.origin
+    }
+    pub fn echo(&self) -> bool {
+        self.origin != Origin::Bus
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rp1210() {
+        let m = J1708Message::new(3, 172, &[84, 100]);
+        let mut buf = [0; 32];
+        let len = m.to_rp1210(&mut buf);
+        assert_eq!(&[3, 172, 84, 100], &buf[..len]);
+
+        let read = [&[0, 0, 0, 20, 1], &buf[1..len]].concat();
+        let r = J1708Message::new_rp1210(&read, &mut AdapterClock::new(1000.0));
+        assert_eq!(172, r.mid());
+        assert_eq!(Origin::Echo, r.origin());
+        assert_eq!("     20.0000 MID 172 [2] 54 64 (TX)", r.to_string());
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use crate::packet::*;
+
+/// J1939-21 transport protocol. Connection management and data transfer, both PDU1.
+pub const TP_CM_PGN: u32 = 0xEC00;
+pub const TP_DT_PGN: u32 = 0xEB00;
+
+const RTS: u8 = 16;
+const CTS: u8 = 17;
+const END_OF_MSG_ACK: u8 = 19;
+const BAM: u8 = 32;
+const ABORT: u8 = 255;
+
+/// Longest gap between BAM data packets
+const BAM_MAX_GAP: Duration = Duration::from_millis(200);
+
+/// Data transfer packets for a message of length bytes, 0 for a single frame
+pub fn packets(length: usize) -> usize {
+    if length <= 8 {
+        0
+    } else {
+        length.div_ceil(7)
+    }
+}
+
+/// Worst case time for the adapter to send a message of length bytes: a BAM at the slowest allowed pace
+pub fn transfer_time(length: usize) -> Duration {
+    BAM_MAX_GAP * packets(length) as u32
+}
+
+/// Is this packet a transport protocol frame?
+pub fn is_transport(packet: &J1939Packet) -> bool {
+    matches!(packet.pgn() & 0x3FF00, TP_CM_PGN | TP_DT_PGN)
+}
+
+/// TP.CM and TP.DT frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    RequestToSend {
+        size: u16,
+        packets: u8,
+        max_per_cts: u8,
+        pgn: u32,
+    },
+    /// count 0 holds the connection open
+    ClearToSend {
+        count: u8,
+        next: u8,
+        pgn: u32,
+    },
+    EndOfMsgAck {
+        size: u16,
+        packets: u8,
+        pgn: u32,
+    },
+    Bam {
+        size: u16,
+        packets: u8,
+        pgn: u32,
+    },
+    Abort {
+        reason: u8,
+        pgn: u32,
+    },
+    Data {
+        seq: u8,
+    },
+}
+
+impl Transport {
+    /// None for other PGNs, unknown control bytes and short frames
+    pub fn parse(packet: &J1939Packet) -> Option<Transport> {
+        let data = packet.data();
+        let pgn_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], 0]);
+        match (packet.pgn() & 0x3FF00, data) {
+            (TP_DT_PGN, [seq, ..]) => Some(Transport::Data { seq: *seq }),
+            (TP_CM_PGN, [control, a, b, c, d, _, _, _, ..]) => {
+                let size = u16::from_le_bytes([*a, *b]);
+                Some(match *control {
+                    RTS => Transport::RequestToSend {
+                        size,
+                        packets: *c,
+                        max_per_cts: *d,
+                        pgn: pgn_at(5),
+                    },
+                    CTS => Transport::ClearToSend {
+                        count: *a,
+                        next: *b,
+                        pgn: pgn_at(5),
+                    },
+                    END_OF_MSG_ACK => Transport::EndOfMsgAck {
+                        size,
+                        packets: *c,
+                        pgn: pgn_at(5),
+                    },
+                    BAM => Transport::Bam {
+                        size,
+                        packets: *c,
+                        pgn: pgn_at(5),
+                    },
+                    ABORT => Transport::Abort {
+                        reason: *a,
+                        pgn: pgn_at(5),
+                    },
+                    _ => return None,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// J1939-21 connection abort reason
+pub fn abort_reason(reason: u8) -> &'static str {
+    match reason {
+        1 => "already in a session",
+        2 => "resources needed elsewhere",
+        3 => "timeout",
+        4 => "CTS while sending",
+        5 => "retransmit limit",
+        6 => "unexpected data packet",
+        7 => "bad sequence number",
+        8 => "duplicate sequence number",
+        9 => "message too large",
+        _ => "other",
+    }
+}
+
+/// What passed on the bus in transport sessions, from the TP.CM and TP.DT frames the adapter reports.
+/// Many DLLs consume these frames, in which case nothing is counted.
+#[derive(Debug, Clone, Default)]
+pub struct TpMonitor {
+    pub bam: u32,
+    pub rts: u32,
+    pub complete: u32,
+    pub data_packets: u32,
+    /// packets granted by each CTS, holds excluded
+    pub windows: Vec<u8>,
+    /// CTS with count 0
+    pub holds: u32,
+    /// CTS asking again for packets already requested
+    pub retransmits: u32,
+    /// ms from the sender's RTS or last data packet to the receiver's CTS
+    pub cts_waits: Vec<f64>,
+    /// reason -> count
+    pub aborts: BTreeMap<u8, u32>,
+    /// (sender, receiver) -> next packet already requested, time of the sender's last frame
+    sessions: HashMap<(u8, u8), (u8, f64)>,
+}
+
+impl TpMonitor {
+    pub fn new() -> TpMonitor {
+        TpMonitor::default()
+    }
+
+    pub fn add(&mut self, packet: &J1939Packet) {
+        let Some(transport) = Transport::parse(packet) else {
+            return;
+        };
+        let (source, dest) = (packet.source(), packet.dest());
+        match transport {
+            Transport::Bam { .. } => self.bam += 1,
+            Transport::RequestToSend { .. } => {
+                self.rts += 1;
+                self.sessions.insert((source, dest), (1, packet.time()));
+            }
+            Transport::Data { .. } => {
+                self.data_packets += 1;
+                if let Some(session) = self.sessions.get_mut(&(source, dest)) {
+                    session.1 = packet.time();
+                }
+            }
+            // from the receiver of the session
+            Transport::ClearToSend { count, next, .. } => {
+                if count == 0 {
+                    self.holds += 1;
+                } else {
+                    self.windows.push(count);
+                }
+                if let Some((requested, last)) = self.sessions.get_mut(&(dest, source)) {
+                    if count > 0 && next < *requested {
+                        self.retransmits += 1;
+                    }
+                    *requested = (*requested).max(next.saturating_add(count));
+                    self.cts_waits.push(packet.time() - *last);
+                }
+            }
+            Transport::EndOfMsgAck { .. } => {
+                self.complete += 1;
+                self.sessions.remove(&(dest, source));
+            }
+            Transport::Abort { reason, .. } => {
+                *self.aborts.entry(reason).or_default() += 1;
+                self.sessions.remove(&(source, dest));
+                self.sessions.remove(&(dest, source));
+            }
+        }
+    }
+
+    /// Sessions started by BAM or RTS
+    pub fn sessions(&self) -> u32 {
+        self.bam + self.rts
+    }
+
+    pub fn aborted(&self) -> u32 {
+        self.aborts.values().sum()
+    }
+
+    pub fn abort_percent(&self) -> f64 {
+        if self.sessions() == 0 {
+            0.0
+        } else {
+            100.0 * self.aborted() as f64 / self.sessions() as f64
+        }
+    }
+}
+
+impl Display for TpMonitor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.sessions() + self.data_packets == 0 {
+            return write!(f, "no transport frames reported by the adapter");
+        }
+        write!(
+            f,
+            "bam: {} rts: {} complete: {} aborted: {} ({:.2}%) data packets: {}",
+            self.bam,
+            self.rts,
+            self.complete,
+            self.aborted(),
+            self.abort_percent(),
+            self.data_packets
+        )?;
+        if let (Some(min), Some(max)) = (self.windows.iter().min(), self.windows.iter().max()) {
+            let mean =
+                self.windows.iter().map(|w| *w as f64).sum::<f64>() / self.windows.len() as f64;
+            write!(
+                f,
+                "\ncts: {} window min: {} avg: {:.1} max: {} holds: {} retransmits: {}",
+                self.windows.len(),
+                min,
+                mean,
+                max,
+                self.holds,
+                self.retransmits
+            )?;
+        }
+        if !self.cts_waits.is_empty() {
+            let max = self.cts_waits.iter().copied().fold(f64::MIN, f64::max);
+            let mean = self.cts_waits.iter().sum::<f64>() / self.cts_waits.len() as f64;
+            write!(f, "\ncts wait avg: {:.4} max: {:.4} ms", mean, max)?;
+        }
+        for (reason, count) in &self.aborts {
+            write!(
+                f,
+                "\nabort {} ({}): {}",
+                reason,
+                abort_reason(*reason),
+                count
+            )?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cm(sa: u8, da: u8, data: [u8; 8]) -> J1939Packet {
+        J1939Packet::new_packet(0x1C, TP_CM_PGN, da, sa, &data)
+    }
+
+    fn dt(sa: u8, da: u8, seq: u8) -> J1939Packet {
+        J1939Packet::new_packet(0x1C, TP_DT_PGN, da, sa, &[seq, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Some(Transport::RequestToSend {
+                size: 100,
+                packets: 15,
+                max_per_cts: 4,
+                pgn: 0xEF00
+            }),
+            Transport::parse(&cm(0xF8, 0xF9, [RTS, 100, 0, 15, 4, 0x00, 0xEF, 0x00]))
+        );
+        assert_eq!(
+            Some(Transport::ClearToSend {
+                count: 4,
+                next: 5,
+                pgn: 0xEF00
+            }),
+            Transport::parse(&cm(0xF9, 0xF8, [CTS, 4, 5, 0xFF, 0xFF, 0x00, 0xEF, 0x00]))
+        );
+        assert_eq!(
+            Some(Transport::Bam {
+                size: 1785,
+                packets: 255,
+                pgn: 0xFFF2
+            }),
+            Transport::parse(&cm(
+                0xF8,
+                0xFF,
+                [BAM, 0xF9, 0x06, 255, 0xFF, 0xF2, 0xFF, 0x00]
+            ))
+        );
+        assert_eq!(
+            Some(Transport::Abort {
+                reason: 3,
+                pgn: 0xEF00
+            }),
+            Transport::parse(&cm(
+                0xF9,
+                0xF8,
+                [ABORT, 3, 0xFF, 0xFF, 0xFF, 0x00, 0xEF, 0x00]
+            ))
+        );
+        assert_eq!(
+            Some(Transport::Data { seq: 7 }),
+            Transport::parse(&dt(0xF8, 0xF9, 7))
+        );
+        assert_eq!(
+            None,
+            Transport::parse(&cm(0xF8, 0xF9, [42, 0, 0, 0, 0, 0, 0, 0]))
+        );
+        assert_eq!(
+            None,
+            Transport::parse(&J1939Packet::new_packet(0x18, 0xFFF1, 0, 0xF8, &[RTS; 8]))
+        );
+        assert!(is_transport(&dt(0xF8, 0xF9, 1)));
+    }
+
+    #[test]
+    fn transfer() {
+        assert_eq!(0, packets(8));
+        assert_eq!(255, packets(1785));
+        assert_eq!(Duration::from_millis(400), transfer_time(9));
+    }
+
+    #[test]
+    fn monitor() {
+        let mut monitor = TpMonitor::new();
+        assert_eq!(
+            "no transport frames reported by the adapter",
+            monitor.to_string()
+        );
+        monitor.add(&cm(0xF8, 0xF9, [RTS, 100, 0, 15, 4, 0x00, 0xEF, 0x00]));
+        monitor.add(&cm(0xF9, 0xF8, [CTS, 4, 1, 0xFF, 0xFF, 0x00, 0xEF, 0x00]));
+        (1..=4).for_each(|seq| monitor.add(&dt(0xF8, 0xF9, seq)));
+        monitor.add(&cm(0xF9, 0xF8, [CTS, 0, 5, 0xFF, 0xFF, 0x00, 0xEF, 0x00]));
+        // packet 3 again
+        monitor.add(&cm(0xF9, 0xF8, [CTS, 2, 3, 0xFF, 0xFF, 0x00, 0xEF, 0x00]));
+        monitor.add(&cm(
+            0xF9,
+            0xF8,
+            [ABORT, 5, 0xFF, 0xFF, 0xFF, 0x00, 0xEF, 0x00],
+        ));
+        monitor.add(&cm(0xF8, 0xFF, [BAM, 9, 0, 2, 0xFF, 0xF2, 0xFF, 0x00]));
+        assert_eq!(2, monitor.sessions());
+        assert_eq!(vec![4, 2], monitor.windows);
+        assert_eq!(1, monitor.holds);
+        assert_eq!(1, monitor.retransmits);
+        assert_eq!(3, monitor.cts_waits.len());
+        assert_eq!(1, monitor.aborted());
+        assert_eq!(50.0, monitor.abort_percent());
+        let text = monitor.to_string();
+        assert!(text.starts_with("bam: 1 rts: 1 complete: 0 aborted: 1 (50.00%) data packets: 4"));
+        assert!(text.contains("cts: 2 window min: 2 avg: 3.0 max: 4 holds: 1 retransmits: 1"));
+        assert!(text.ends_with("abort 5 (retransmit limit): 1"));
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::*;
+use std::fmt::Display;
+
+use crate::packet::*;
+
+/// J1939-22 Multi-PG. Several contained parameter groups (C-PGs) packed into one CAN FD frame.
+pub const MULTI_PG_PGN: u32 = 0x2500;
+
+/// C-PG header: tos[3 bits] tf[3 bits] cpgn[18 bits] (little endian), payload length[1]
+const HEADER_LEN: usize = 4;
+/// fill for unused bytes
+const PADDING: u8 = 0xAA;
+
+/// Contained parameter group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainedPg {
+    /// type of service
+    pub tos: u8,
+    /// trailer format
+    pub tf: u8,
+    pub pgn: u32,
+    pub data: Vec<u8>,
+}
+
+impl Display for ContainedPg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "C-PG {:05X} tos: {} tf: {} [{}]",
+            self.pgn,
+            self.tos,
+            self.tf,
+            self.data.len()
+        )?;
+        for b in &self.data {
+            write!(f, " {:02X}", b)?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+/// Is this packet a Multi-PG container?
+pub fn is_multi_pg(packet: &J1939Packet) -> bool {
+    packet.pgn() & 0x3FF00 == MULTI_PG_PGN
+}
+
+/// Parse the C-PGs from a Multi-PG payload. Parsing stops at padding.
+pub fn parse(data: &[u8]) -> Result<Vec<ContainedPg>> {
+    let mut rtn = Vec::new();
+    let mut rest = data;
+    while rest.len() >= HEADER_LEN && rest[..HEADER_LEN] != [PADDING; HEADER_LEN] {
+        let header = u32::from_le_bytes([rest[0], rest[1], rest[2], 0]);
+        let tos = (header >> 21) as u8;
+        if tos == 0 {
+            break;
+        }
+        let len = rest[3] as usize;
+        if HEADER_LEN + len > rest.len() {
+            bail!(
+                "C-PG {:05X} length {} exceeds remaining {} bytes",
+                header & 0x3FFFF,
+                len,
+                rest.len() - HEADER_LEN
+            );
+        }
+        rtn.push(ContainedPg {
+            tos,
+            tf: ((header >> 18) & 0x07) as u8,
+            pgn: header & 0x3FFFF,
+            data: rest[HEADER_LEN..HEADER_LEN + len].to_vec(),
+        });
+        rest = &rest[HEADER_LEN + len..];
+    }
+    Ok(rtn)
+}
+
+/// Build a Multi-PG payload, padded to the next valid CAN FD length.
+pub fn build(pgs: &[ContainedPg]) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(64);
+    for pg in pgs {
+        if pg.tos == 0 || pg.tos > 7 || pg.tf > 7 || pg.pgn > 0x3FFFF {
+            bail!("Invalid C-PG header {:?}", pg);
+        }
+        let header = ((pg.tos as u32) << 21) | ((pg.tf as u32) << 18) | pg.pgn;
+        buf.extend_from_slice(&header.to_le_bytes()[0..3]);
+        buf.push(pg.data.len() as u8);
+        buf.extend_from_slice(&pg.data);
+    }
+    let len = fd_dlc(buf.len())
+        .map(|dlc| FD_LENGTHS[dlc as usize])
+        .ok_or_else(|| anyhow!("Multi-PG payload of {} bytes exceeds 64", buf.len()))?;
+    buf.resize(len, PADDING);
+    Ok(buf)
+}
+
+/// Build a Multi-PG CAN FD packet
+#[allow(dead_code)]
+pub fn multi_pg_packet(
+    priority: u8,
+    da: u8,
+    sa: u8,
+    pgs: &[ContainedPg],
+    flags: FdFlags,
+) -> Result<J1939Packet> {
+    Ok(J1939Packet::new_packet(priority, MULTI_PG_PGN, da, sa, &build(pgs)?).with_fd(flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let pgs = vec![
+            ContainedPg {
+                tos: 2,
+                tf: 0,
+                pgn: 0xF004,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            },
+            ContainedPg {
+                tos: 2,
+                tf: 1,
+                pgn: 0x1FEF1,
+                data: vec![9, 10, 11],
+            },
+        ];
+        let buf = build(&pgs).unwrap();
+        // 2 headers + 11 bytes = 19, padded to 20
+        assert_eq!(20, buf.len());
+        assert_eq!(PADDING, buf[19]);
+        assert_eq!(pgs, parse(&buf).unwrap());
+    }
+
+    #[test]
+    fn too_long() {
+        let pg = ContainedPg {
+            tos: 1,
+            tf: 0,
+            pgn: 0xFEF1,
+            data: vec![0; 61],
+        };
+        assert!(build(std::slice::from_ref(&pg)).is_err());
+        let mut buf = build(&[ContainedPg {
+            data: vec![0; 8],
+            ..pg
+        }])
+        .unwrap();
+        buf[3] = 20;
+        assert!(parse(&buf).is_err());
+    }
+
+    #[test]
+    fn packet() {
+        let p = multi_pg_packet(
+            0x18,
+            0x25,
+            0xF9,
+            &[ContainedPg {
+                tos: 2,
+                tf: 0,
+                pgn: 0xF004,
+                data: vec![1; 8],
+            }],
+            FdFlags::default(),
+        )
+        .unwrap();
+        assert!(is_multi_pg(&p));
+        assert_eq!(12, p.length());
+        assert_eq!(1, parse(p.data()).unwrap().len());
+    }
+}
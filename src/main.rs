@@ -1,28 +1,53 @@
+mod can;
+mod clock;
+// command and health serve the Windows binding, which the simulator build leaves out
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+mod command;
+mod control;
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+mod health;
+mod isotp;
+mod j1587;
+mod j1708;
+mod j1939_21;
+mod j1939_22;
+mod matrix;
 mod multiqueue;
+mod pacing;
 mod packet;
-#[cfg_attr(
-    not(all(target_pointer_width = "32", target_os = "windows")),
-    path = "sim.rs"
-)]
-#[cfg_attr(
-    all(target_pointer_width = "32", target_os = "windows"),
-    path = "rp1210.rs"
-)]
+mod pattern;
+#[cfg_attr(not(target_os = "windows"), path = "sim.rs")]
+#[cfg_attr(target_os = "windows", path = "rp1210.rs")]
 mod rp1210;
 mod rp1210_parsing;
+mod stats;
+mod uds;
 
 use anyhow::Error;
-use clap::{arg, Parser};
+use can::*;
+use clap::Parser;
+use control::*;
+use health::ReconnectPolicy;
+use isotp::IsoTp;
+use j1708::J1708Message;
+use j1939_21::TpMonitor;
+use matrix::{AdapterPlan, MatrixReport, Outcome, Plan, TestKind};
 use multiqueue::*;
+use pacing::{target_rates, Pacer};
 use packet::*;
+use pattern::{stuff_bits, Pattern, MAX_LENGTH};
 use rp1210::*;
-use std::time::{Duration, SystemTime};
-
-const PING_CMD: u8 = 1;
-const RX_CMD: u8 = 2;
-const TX_CMD: u8 = 3;
-const DATA_CMD: u8 = 4;
-const EXIT_CMD: u8 = 5;
+use rp1210_parsing::{IniDirs, Protocol};
+use stats::{LatencyStats, RateReport, RxSummary, SeqTracker};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::mpsc::{Receiver, SendError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use uds::Uds;
 
 #[derive(Parser, Debug, Default, Clone)]
 struct ConnectionDescriptor {
@@ -33,7 +58,7 @@ struct ConnectionDescriptor {
     device: u8,
 
     #[arg(long, default_value = "J1939:Baud=Auto")]
-    /// RP1210 Connection String
+    /// RP1210 Connection String (J1939FD for CAN FD networks, CAN for raw CAN, J1708 for J1587)
     connection_string: String,
 
     #[arg(long, default_value = "F9",value_parser=hex8)]
@@ -42,34 +67,127 @@ struct ConnectionDescriptor {
 
     #[arg(long, short, default_value = "false")]
     verbose: bool,
+
+    /// Directory with RP121032.ini/RP121064.ini and the vendor INIs (default RP1210_INI_DIR, then the platform default)
+    #[arg(long)]
+    ini_dir: Option<PathBuf>,
+
+    /// Do not reconnect after the adapter disconnects
+    #[arg(long)]
+    no_reconnect: bool,
+}
+
+/// Test frame payload for J1939 tx, rx and duplex
+#[derive(Parser, Debug, Clone)]
+struct PayloadDescriptor {
+    /// Payload bytes, 0 to 1785. Over 8 uses the adapter's transport protocol.
+    #[arg(long, default_value = "8")]
+    length: u16,
+
+    /// Payload pattern. Every pattern but sequence is sent on --data-pgn.
+    #[arg(long, value_enum, default_value = "sequence")]
+    pattern: Pattern,
+
+    /// Seed for the random pattern
+    #[arg(long, default_value = "0")]
+    seed: u32,
+
+    /// PGN of patterned test frames. Concurrent clients of one server need different ones.
+    #[arg(long, default_value = "FFF2",value_parser=hex32)]
+    data_pgn: u32,
+}
+
+impl PayloadDescriptor {
+    fn params(&self, count: u32, rate: Option<f64>, idle: Duration) -> TestParams {
+        TestParams {
+            count,
+            rate,
+            pattern: self.pattern,
+            length: self.length,
+            seed: self.seed,
+            data_pgn: (self.pattern != Pattern::Sequence).then_some(self.data_pgn),
+            idle,
+        }
+    }
+
+    /// Stuff bits in the first frame's data, which bit stuffing adds to every frame
+    fn print_stuffing(&self) {
+        let data = self.params(1, None, IDLE_TIMEOUT).payload(0, 0);
+        eprintln!(
+            "{:?} payload of {} bytes: {} stuff bits in the first 8",
+            self.pattern,
+            self.length,
+            stuff_bits(&data[..data.len().min(8)])
+        );
+    }
+}
+
+/// First Ctrl-C stops the adapter, which drains and ends the command so output is complete.
+/// A second Ctrl-C exits immediately.
+fn stop_on_ctrl_c(running: Arc<AtomicBool>) -> Result<(), Error> {
+    ctrlc::set_handler(move || {
+        if running.swap(false, Relaxed) {
+            eprintln!("Stopping...");
+        } else {
+            std::process::exit(130);
+        }
+    })?;
+    Ok(())
 }
 
 fn hex8(str: &str) -> Result<u8, std::num::ParseIntError> {
     u8::from_str_radix(str, 16)
 }
 
+fn hex16(str: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(str, 16)
+}
+
 fn hex32(str: &str) -> Result<u32, std::num::ParseIntError> {
     u32::from_str_radix(str, 16)
 }
 
 impl ConnectionDescriptor {
     fn connect(&self, bus: &MultiQueue<J1939Packet>) -> Result<Rp1210, Error> {
+        let rp1210 = self.open(bus)?;
+        stop_on_ctrl_c(rp1210.running.clone())?;
+        Ok(rp1210)
+    }
+
+    /// connect() without the Ctrl-C handler, for more than one adapter in a process
+    fn open(&self, bus: &MultiQueue<J1939Packet>) -> Result<Rp1210, Error> {
         let mut rp1210 = Rp1210::new(
             &self.adapter,
             self.device as i16,
             &self.connection_string,
             self.address,
             bus.clone(),
+            &IniDirs::new(self.ini_dir.clone()),
         )?;
+        if self.no_reconnect {
+            rp1210.reconnect = ReconnectPolicy::disabled();
+        }
         rp1210.run();
         Ok(rp1210)
     }
 }
 
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum ListFormat {
+    Table,
+    Json,
+}
+
 #[derive(Parser, Debug, Clone)]
 enum RPCommand {
     /// List available RP1210 adapters
-    List,
+    List {
+        #[arg(long, value_enum, default_value = "table")]
+        format: ListFormat,
+        /// Directory with RP121032.ini/RP121064.ini and the vendor INIs (default RP1210_INI_DIR, then the platform default)
+        #[arg(long)]
+        ini_dir: Option<PathBuf>,
+    },
     /// request server to exit
     Exit {
         #[command(flatten)]
@@ -79,10 +197,18 @@ enum RPCommand {
         #[arg(long, default_value = "FFF1",value_parser=hex32)]
         pgn: u32,
     },
+    /// Report adapter versions and hardware status
+    Info {
+        #[command(flatten)]
+        connection: ConnectionDescriptor,
+    },
     /// Log all traffic on specified adapter
     Log {
         #[command(flatten)]
         connection: ConnectionDescriptor,
+        /// Prefix each packet with the host wall clock time (UTC)
+        #[arg(long)]
+        wall_clock: bool,
     },
     /// Respond to commands from other instances of rp1210test
     Server {
@@ -101,6 +227,12 @@ enum RPCommand {
         count: u32,
         #[arg(long, default_value = "FFF1",value_parser=hex32)]
         pgn: u32,
+        /// Pings outstanding at once (1 = stop and wait)
+        #[arg(short, long, default_value = "1")]
+        window: usize,
+        /// Write every round trip time to this CSV file (seq,rtt_ms)
+        #[arg(long)]
+        samples: Option<PathBuf>,
     },
     /// Composite
     Composite {
@@ -123,6 +255,20 @@ enum RPCommand {
         count: u32,
         #[arg(long, default_value = "FFF1",value_parser=hex32)]
         pgn: u32,
+        /// CAN ID for raw CAN connections (default 18<pgn><address>)
+        #[arg(long, value_parser=hex32)]
+        can_id: Option<u32>,
+        /// Frames per second, comma separated for a sweep (default as fast as possible)
+        #[arg(long, value_delimiter = ',')]
+        rate: Vec<f64>,
+        /// Bus load percent of --length byte messages, transport frames included, comma separated for a sweep
+        #[arg(long, value_delimiter = ',')]
+        bus_load: Vec<f64>,
+        /// Seconds without a frame before the receiver reports what it has
+        #[arg(long, default_value = "2")]
+        idle_timeout: f64,
+        #[command(flatten)]
+        payload: PayloadDescriptor,
     },
     /// Test receiving bandwidth
     Rx {
@@ -134,6 +280,116 @@ enum RPCommand {
         count: u32,
         #[arg(long, default_value = "FFF1",value_parser=hex32)]
         pgn: u32,
+        /// CAN ID for raw CAN connections (default 18<pgn><dest>)
+        #[arg(long, value_parser=hex32)]
+        can_id: Option<u32>,
+        /// Frames per second, comma separated for a sweep (default as fast as possible)
+        #[arg(long, value_delimiter = ',')]
+        rate: Vec<f64>,
+        /// Bus load percent of --length byte messages, transport frames included, comma separated for a sweep
+        #[arg(long, value_delimiter = ',')]
+        bus_load: Vec<f64>,
+        /// Seconds without a frame before the receiver reports what it has
+        #[arg(long, default_value = "2")]
+        idle_timeout: f64,
+        #[command(flatten)]
+        payload: PayloadDescriptor,
+    },
+    /// Test sending and receiving bandwidth at the same time
+    Duplex {
+        #[command(flatten)]
+        connection: ConnectionDescriptor,
+        #[arg(long, default_value = "00",value_parser=hex8)]
+        dest: u8,
+        #[arg(short, long)]
+        count: u32,
+        #[arg(long, default_value = "FFF1",value_parser=hex32)]
+        pgn: u32,
+        /// Frames per second each way, comma separated for a sweep (default as fast as possible)
+        #[arg(long, value_delimiter = ',')]
+        rate: Vec<f64>,
+        /// Bus load percent of --length byte messages each way, transport frames included, comma separated for a sweep
+        #[arg(long, value_delimiter = ',')]
+        bus_load: Vec<f64>,
+        /// Seconds without a frame before the receiver reports what it has
+        #[arg(long, default_value = "2")]
+        idle_timeout: f64,
+        #[command(flatten)]
+        payload: PayloadDescriptor,
+    },
+    /// Test transport protocol (BAM or RTS/CTS) messages both ways
+    Transport {
+        #[command(flatten)]
+        connection: ConnectionDescriptor,
+        #[arg(long, default_value = "00",value_parser=hex8)]
+        dest: u8,
+        /// Messages of each size each way
+        #[arg(short, long, default_value = "10")]
+        count: u32,
+        #[arg(long, default_value = "FFF1",value_parser=hex32)]
+        pgn: u32,
+        /// Message sizes in bytes, 9 to 1785, comma separated
+        #[arg(long, value_delimiter = ',', default_value = "9,100,1785")]
+        size: Vec<u16>,
+        /// Broadcast (BAM) instead of RTS/CTS to the server
+        #[arg(long)]
+        bam: bool,
+        /// PGN of the messages, PDU2 for BAM and PDU1 for RTS/CTS (default FFF2 or EF00)
+        #[arg(long, value_parser=hex32)]
+        data_pgn: Option<u32>,
+        /// Messages per second (default as fast as the adapter sends them)
+        #[arg(long)]
+        rate: Option<f64>,
+        /// Seconds without a message, on top of the slowest transfer time, before the receiver reports what it has
+        #[arg(long, default_value = "2")]
+        idle_timeout: f64,
+    },
+    /// Run a TOML test plan of servers and clients on several adapters and compare them
+    Matrix {
+        /// Test plan, see matrix.toml
+        plan: PathBuf,
+        #[arg(long, short, default_value = "false")]
+        verbose: bool,
+    },
+    /// Send J1587 parameters on a J1708 connection, with the PID 197/198 transport to --dest when they
+    /// don't fit in one message
+    J1587 {
+        #[command(flatten)]
+        connection: ConnectionDescriptor,
+        /// MID to send from
+        #[arg(long, default_value = "182")]
+        mid: u8,
+        /// MID to send to with the transport
+        #[arg(long)]
+        dest: Option<u8>,
+        /// Parameter bytes in hex, PIDs included, i.e. 54 64 for road speed
+        #[arg(required = true, value_parser=hex8)]
+        data: Vec<u8>,
+    },
+    /// UDS diagnostics over ISO-TP (ISO 15765)
+    Uds {
+        #[command(flatten)]
+        connection: ConnectionDescriptor,
+        #[arg(long, default_value = "00",value_parser=hex8)]
+        dest: u8,
+        /// DiagnosticSessionControl session type
+        #[arg(long, value_parser=hex8)]
+        session: Option<u8>,
+        /// SecurityAccess requestSeed level (odd)
+        #[arg(long, value_parser=hex8)]
+        security_level: Option<u8>,
+        /// Program that is passed the seed as hex and prints the key as hex
+        #[arg(long)]
+        key_command: Option<String>,
+        /// ReadDataByIdentifier DID (repeatable)
+        #[arg(long, value_parser=hex16)]
+        did: Vec<u16>,
+        /// ReadDTCInformation status mask
+        #[arg(long, value_parser=hex8)]
+        dtc_mask: Option<u8>,
+        /// Keep the session alive with TesterPresent for this many seconds
+        #[arg(long, default_value = "0")]
+        hold: u64,
     },
 }
 
@@ -142,33 +398,56 @@ pub fn main() -> Result<(), Error> {
 
     let bus: MultiQueue<J1939Packet> = MultiQueue::new();
     match args {
-        RPCommand::List => list_adapters()?,
+        RPCommand::List { format, ini_dir } => list_adapters(format, &IniDirs::new(ini_dir))?,
         RPCommand::Exit {
             connection,
             pgn,
             dest,
-        } => request_exit(&connection.connect(&bus)?, pgn, dest, connection.address)?,
-        RPCommand::Log { connection } => {
-            let _connect = connection.connect(&bus)?;
-            log(&bus);
+        } => {
+            let rp1210 = connection.connect(&bus)?;
+            request_exit(&ControlClient::connect(
+                &rp1210,
+                connection.address,
+                dest,
+                pgn,
+            )?)?
+        }
+        RPCommand::Info { connection } => {
+            print!("{}", connection.connect(&bus)?.info()?);
+        }
+        RPCommand::Log {
+            connection,
+            wall_clock,
+        } => {
+            let mut rp1210 = connection.connect(&bus)?;
+            let events = print_events(&rp1210);
+            log(&rp1210, wall_clock);
+            rp1210.close();
+            events.join().unwrap();
         }
         RPCommand::Server { connection, pgn } => {
-            server(&connection.connect(&bus)?, connection.address, pgn)?;
+            let mut rp1210 = connection.connect(&bus)?;
+            let events = print_events(&rp1210);
+            let requests = server_requests(&rp1210, connection.address, pgn);
+            let result = server(&rp1210, connection.address, pgn, requests);
+            rp1210.close();
+            events.join().unwrap();
+            result?;
         }
         RPCommand::Ping {
             connection,
             dest,
             count,
             pgn,
+            window,
+            samples,
         } => {
-            ping(
-                connection.verbose,
-                &connection.connect(&bus)?,
-                count,
-                connection.address,
-                pgn,
-                dest,
-            )?;
+            let rp1210 = connection.connect(&bus)?;
+            let control = ControlClient::connect(&rp1210, connection.address, dest, pgn)?;
+            let stats = ping(connection.verbose, &control, count, window)?;
+            if let Some(path) = samples {
+                stats.write_samples(&mut std::io::BufWriter::new(std::fs::File::create(path)?))?;
+            }
         }
         RPCommand::Composite {
             connection,
@@ -177,281 +456,1072 @@ pub fn main() -> Result<(), Error> {
             pgn,
         } => {
             let rp1210 = connection.connect(&bus)?;
-            ping(
-                connection.verbose,
-                &rp1210,
-                count,
-                connection.address,
-                pgn,
-                dest,
-            )?;
-            tx_bandwidth(
-                connection.verbose,
-                &rp1210,
+            let control = ControlClient::connect(&rp1210, connection.address, dest, pgn)?;
+            ping(connection.verbose, &control, count, 1)?;
+            let params = TestParams {
                 count,
-                connection.address,
-                pgn,
-                dest,
-            )?;
-            rx_bandwidth(
-                connection.verbose,
-                &rp1210,
-                count,
-                connection.address,
-                pgn,
-                dest,
-            )?;
+                ..Default::default()
+            };
+            println!("tx {}", RateReport::HEADER);
+            let report = tx_bandwidth(connection.verbose, &control, &params)?;
+            println!("tx {}", report);
+            println!("rx {}", RateReport::HEADER);
+            let report = rx_bandwidth(connection.verbose, &control, &params)?;
+            println!("rx {}", report);
         }
         RPCommand::Rx {
             connection,
             dest,
             count,
             pgn,
+            can_id,
+            rate,
+            bus_load,
+            idle_timeout,
+            payload,
         } => {
-            rx_bandwidth(
-                connection.verbose,
-                &connection.connect(&bus)?,
-                count,
-                connection.address,
-                pgn,
-                dest,
-            )?;
+            let rp1210 = connection.connect(&bus)?;
+            let idle = Duration::from_secs_f64(idle_timeout);
+            if rp1210.protocol == Protocol::CAN {
+                // the sender sets the rate
+                let id = can_id.unwrap_or(0x18000000 | (pgn << 8) | dest as u32);
+                let summary = can_rx(connection.verbose, &rp1210, id, count, idle)?;
+                eprintln!("{}", summary);
+                println!("rx {}", RateReport::HEADER);
+                println!("rx {}", summary.report(None));
+            } else {
+                let control = ControlClient::connect(&rp1210, connection.address, dest, pgn)?;
+                payload.print_stuffing();
+                println!("rx {}", RateReport::HEADER);
+                let rates = target_rates(
+                    &rate,
+                    &bus_load,
+                    &connection.connection_string,
+                    payload.length as usize,
+                );
+                for rate in rates {
+                    let params = payload.params(count, rate, idle);
+                    let report = rx_bandwidth(connection.verbose, &control, &params)?;
+                    println!("rx {}", report);
+                }
+            }
         }
         RPCommand::Tx {
             connection,
             dest,
             count,
             pgn,
+            can_id,
+            rate,
+            bus_load,
+            idle_timeout,
+            payload,
         } => {
-            tx_bandwidth(
-                connection.verbose,
-                &connection.connect(&bus)?,
-                count,
-                connection.address,
-                pgn,
-                dest,
-            )?;
+            let rp1210 = connection.connect(&bus)?;
+            let idle = Duration::from_secs_f64(idle_timeout);
+            if rp1210.protocol == Protocol::CAN {
+                let rates = target_rates(&rate, &bus_load, &connection.connection_string, 8);
+                let id = can_id.unwrap_or(0x18000000 | (pgn << 8) | connection.address as u32);
+                println!("tx {}", RateReport::HEADER);
+                for rate in rates {
+                    let report = can_tx(connection.verbose, &rp1210, id, count, rate)?;
+                    println!("tx {}", report);
+                }
+            } else {
+                let control = ControlClient::connect(&rp1210, connection.address, dest, pgn)?;
+                payload.print_stuffing();
+                println!("tx {}", RateReport::HEADER);
+                let rates = target_rates(
+                    &rate,
+                    &bus_load,
+                    &connection.connection_string,
+                    payload.length as usize,
+                );
+                for rate in rates {
+                    let params = payload.params(count, rate, idle);
+                    let report = tx_bandwidth(connection.verbose, &control, &params)?;
+                    println!("tx {}", report);
+                }
+            }
+        }
+        RPCommand::Duplex {
+            connection,
+            dest,
+            count,
+            pgn,
+            rate,
+            bus_load,
+            idle_timeout,
+            payload,
+        } => {
+            let rp1210 = connection.connect(&bus)?;
+            let idle = Duration::from_secs_f64(idle_timeout);
+            let control = ControlClient::connect(&rp1210, connection.address, dest, pgn)?;
+            payload.print_stuffing();
+            println!("   {}", RateReport::HEADER);
+            let rates = target_rates(
+                &rate,
+                &bus_load,
+                &connection.connection_string,
+                payload.length as usize,
+            );
+            for rate in rates {
+                let params = payload.params(count, rate, idle);
+                let (tx, rx) = duplex(connection.verbose, &control, &params)?;
+                println!("tx {}\nrx {}", tx, rx);
+                if let Some(skew) = tx.skew_line() {
+                    println!("tx {}", skew);
+                }
+            }
+        }
+        RPCommand::Transport {
+            connection,
+            dest,
+            count,
+            pgn,
+            size,
+            bam,
+            data_pgn,
+            rate,
+            idle_timeout,
+        } => {
+            let data_pgn = data_pgn.unwrap_or(if bam { 0xFFF2 } else { 0xEF00 });
+            if bam == is_pdu1(data_pgn) {
+                anyhow::bail!(
+                    "BAM needs a PDU2 --data-pgn and RTS/CTS a PDU1 one, not {:04X}",
+                    data_pgn
+                );
+            }
+            if let Some(s) = size
+                .iter()
+                .find(|s| !(9..=MAX_LENGTH).contains(&(**s as usize)))
+            {
+                anyhow::bail!(
+                    "--size {} is not a transport message of 9 to {}",
+                    s,
+                    MAX_LENGTH
+                );
+            }
+            let rp1210 = connection.connect(&bus)?;
+            let control = ControlClient::connect(&rp1210, connection.address, dest, pgn)?;
+            println!("         {} {:>10}", RateReport::HEADER, "bytes/s");
+            for size in size {
+                let params = TestParams {
+                    count,
+                    rate,
+                    // every message differs, so echoes and retransmits can't be confused
+                    pattern: Pattern::Random,
+                    length: size,
+                    seed: size as u32,
+                    data_pgn: Some(data_pgn),
+                    idle: Duration::from_secs_f64(idle_timeout),
+                };
+                let tests = [
+                    ("tx", tx_bandwidth as fn(_, &_, &_) -> _),
+                    ("rx", rx_bandwidth),
+                ];
+                for (direction, test) in tests {
+                    let mut frames = transport_frames(&control);
+                    let report: RateReport = test(connection.verbose, &control, &params)?;
+                    println!(
+                        "{} {:>5} {} {:>10.0}",
+                        direction,
+                        size,
+                        report,
+                        report.achieved() * size as f64
+                    );
+                    let mut monitor = TpMonitor::new();
+                    while let Some(p) = frames.next_timeout(Duration::ZERO) {
+                        monitor.add(&p);
+                    }
+                    for line in monitor.to_string().lines() {
+                        println!("{} {:>5} {}", direction, size, line);
+                    }
+                }
+            }
+        }
+        RPCommand::Matrix { plan, verbose } => {
+            let report = matrix(&Plan::load(&plan)?, verbose)?;
+            println!("{}", report);
+            if report.failures() > 0 {
+                anyhow::bail!(
+                    "{} of {} tests failed",
+                    report.failures(),
+                    report.rows.len()
+                );
+            }
+        }
+        RPCommand::J1587 {
+            connection,
+            mid,
+            dest,
+            data,
+        } => {
+            let rp1210 = connection.connect(&bus)?;
+            if data.len() <= j1708::MAX_DATA {
+                println!("{}", rp1210.send_j1708(&J1708Message::new(8, mid, &data))?);
+            } else {
+                let dest = dest.ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "--dest is needed to send more than {} bytes",
+                        j1708::MAX_DATA
+                    )
+                })?;
+                j1587::send_transport(&rp1210, mid, dest, &data)?;
+                println!("{} bytes sent to MID {}", data.len(), dest);
+            }
+        }
+        RPCommand::Uds {
+            connection,
+            dest,
+            session,
+            security_level,
+            key_command,
+            did,
+            dtc_mask,
+            hold,
+        } => {
+            let rp1210 = connection.connect(&bus)?;
+            let uds = Uds::new(IsoTp::new(&rp1210, connection.address, dest));
+            if let Some(session) = session {
+                uds.diagnostic_session_control(session)?;
+                println!("session {:02X}", session);
+            }
+            if let Some(level) = security_level {
+                let key_command = key_command
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("--security-level requires --key-command"))?;
+                uds.security_access(level, |seed| key_from_command(key_command, seed))?;
+                println!("security level {:02X} unlocked", level);
+            }
+            for did in did {
+                println!(
+                    "{:04X}: {}",
+                    did,
+                    as_hex(&uds.read_data_by_identifier(did)?)
+                );
+            }
+            if let Some(mask) = dtc_mask {
+                for dtc in uds.read_dtcs_by_status_mask(mask)? {
+                    println!("{}", dtc);
+                }
+            }
+            if hold > 0 {
+                let running = AtomicBool::new(true);
+                std::thread::scope(|s| {
+                    let keep_alive = s.spawn(|| uds.keep_alive(Duration::from_secs(2), &running));
+                    std::thread::sleep(Duration::from_secs(hold));
+                    running.store(false, Relaxed);
+                    keep_alive.join().unwrap()
+                })?;
+            }
         }
     }
+    // the adapter has been closed and drained by now
+    std::io::stdout().flush()?;
     Ok(())
 }
 
-fn request_exit(connection: &Rp1210, pgn: u32, dest: u8, address: u8) -> Result<(), Error> {
-    let request = [EXIT_CMD, 0, 0, 0, 0, 0, 0, 0];
-    let sent = connection.send(&J1939Packet::new_packet(0x18, pgn, address, dest, &request))?;
-    println!("EXIT requested {}", sent);
+/// Run the external seed to key program: seed as a hex argument, key as hex on stdout
+fn key_from_command(command: &str, seed: &[u8]) -> Result<Vec<u8>, Error> {
+    let output = std::process::Command::new(command)
+        .arg(
+            seed.iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<String>(),
+        )
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!("{} failed: {}", command, output.status);
+    }
+    let key = String::from_utf8(output.stdout)?;
+    let key: String = key.chars().filter(|c| c.is_ascii_hexdigit()).collect();
+    if key.is_empty() || !key.len().is_multiple_of(2) {
+        anyhow::bail!(
+            "{} printed {} hex digits, expected whole bytes of key",
+            command,
+            key.len()
+        );
+    }
+    (0..key.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&key[i..i + 2], 16)?))
+        .collect()
+}
+
+fn request_exit(control: &ControlClient) -> Result<(), Error> {
+    control.request(&Message::Exit)?;
+    println!("EXIT acknowledged by {:02X}", control.dest);
     Ok(())
 }
 
 fn tx_bandwidth(
     verbose: bool,
-    rp1210: &Rp1210,
-    count: u32,
-    address: u8,
-    pgn: u32,
-    dest: u8,
-) -> Result<(), Error> {
-    let request: Vec<u8> = [RX_CMD, 0, 0, 0]
-        .into_iter()
-        .chain((count as u32).to_be_bytes().into_iter())
-        .collect();
-    let req = rp1210.send(&J1939Packet::new_packet(0x18, pgn, dest, address, &request))?;
-    let last = tx(verbose, &rp1210, pgn, dest, address, count)?;
-    let time = last.time() - req.time();
-    eprintln!(
-        "tx time: {:8.4} packet/s: {:8.4}",
-        time,
-        1000.0 * count as f64 / time
-    );
-    Ok(())
+    control: &ControlClient,
+    params: &TestParams,
+) -> Result<RateReport, Error> {
+    control.require(CAP_BANDWIDTH | CAP_SUMMARY)?;
+    let mut replies = control.replies();
+    let (token, _) = control.request(&Message::Rx(params.clone()))?;
+    let mut report = tx(
+        verbose,
+        control.rp1210,
+        control.pgn,
+        control.address,
+        control.dest,
+        control.address,
+        params,
+    )?;
+    // the server reports what it received once it has everything or goes idle
+    match control.wait(
+        &mut replies,
+        token,
+        SUMMARY_CMD,
+        params.idle_timeout() + Duration::from_secs(5),
+    )? {
+        Message::Summary(summary) => {
+            eprintln!("server {}", summary);
+            report.received = summary.received;
+            report.out_of_order = summary.out_of_order;
+        }
+        m => anyhow::bail!("Unexpected reply {:?}", m),
+    }
+    Ok(report)
 }
 
 fn rx_bandwidth(
     verbose: bool,
-    rp1210: &Rp1210,
-    count: u32,
-    address: u8,
-    pgn: u32,
-    dest: u8,
-) -> Result<(), Error> {
-    let rx_packets = rp1210.bus.iter();
-    let request: Vec<u8> = [TX_CMD, 0, 0, 0]
-        .into_iter()
-        .chain((count as u32).to_be_bytes().into_iter())
-        .collect();
-    let req = rp1210.send(&J1939Packet::new_packet(0x18, pgn, dest, address, &request))?;
-    let last = rx(verbose, rx_packets, pgn, dest, count)?;
-    let time = last.time() - req.time();
-    eprintln!(
-        "rx time: {:8.4} packet/s: {:8.4}",
-        time,
-        1000.0 * count as f64 / time
-    );
-    Ok(())
+    control: &ControlClient,
+    params: &TestParams,
+) -> Result<RateReport, Error> {
+    control.require(CAP_BANDWIDTH)?;
+    let rx_packets = server_frames(control, params);
+    control.request(&Message::Tx(params.clone()))?;
+    let summary = rx(verbose, rx_packets, params, control.address)?;
+    eprintln!("{}", summary);
+    Ok(summary.report(params.rate))
+}
+
+/// The server's test frames for this client, until idle
+fn server_frames(
+    control: &ControlClient,
+    params: &TestParams,
+) -> impl Iterator<Item = J1939Packet> {
+    let session = control.session_filter();
+    let sequence = params.pattern == Pattern::Sequence;
+    // patterned frames carry no session, so only the PGN and source tell them apart
+    let data = params.data_filter(control.pgn, control.dest);
+    control
+        .rp1210
+        .bus
+        .iter_filtered_idle(params.idle_timeout(), move |p: &J1939Packet| {
+            if sequence {
+                session(p)
+            } else {
+                data.matches(p)
+            }
+        })
 }
 
+/// TP.CM and TP.DT frames between the client and server
+fn transport_frames(control: &ControlClient) -> MqIter<J1939Packet> {
+    let (client, server) = (control.address, control.dest);
+    control.rp1210.bus.subscribe(move |p: &J1939Packet| {
+        j1939_21::is_transport(p) && (p.source() == client || p.source() == server)
+    })
+}
+
+/// tx_bandwidth and rx_bandwidth at the same time. Returns the tx and rx reports.
+fn duplex(
+    verbose: bool,
+    control: &ControlClient,
+    params: &TestParams,
+) -> Result<(RateReport, RateReport), Error> {
+    control.require(CAP_DUPLEX | CAP_SUMMARY)?;
+    let mut replies = control.replies();
+    let rx_packets = server_frames(control, params);
+    let (token, _) = control.request(&Message::Duplex(params.clone()))?;
+    let (report, summary) = std::thread::scope(|scope| {
+        let rx = scope.spawn(move || rx(verbose, rx_packets, params, control.address));
+        let report = tx(
+            verbose,
+            control.rp1210,
+            control.pgn,
+            control.address,
+            control.dest,
+            control.address,
+            params,
+        );
+        (report, rx.join())
+    });
+    let mut tx_report = report?;
+    let summary = summary.map_err(|_| anyhow::anyhow!("rx panicked"))??;
+    eprintln!("{}", summary);
+    match control.wait(
+        &mut replies,
+        token,
+        SUMMARY_CMD,
+        params.idle_timeout() + Duration::from_secs(5),
+    )? {
+        Message::Summary(server) => {
+            eprintln!("server {}", server);
+            tx_report.received = server.received;
+            tx_report.out_of_order = server.out_of_order;
+        }
+        m => anyhow::bail!("Unexpected reply {:?}", m),
+    }
+    Ok((tx_report, summary.report(params.rate)))
+}
+
+/// Ping with up to window requests outstanding. Responses are matched by the sequence in the payload.
 fn ping(
     verbose: bool,
-    rp1210: &Rp1210,
+    control: &ControlClient,
     count: u32,
-    address: u8,
-    pgn: u32,
-    dest: u8,
-) -> Result<(), Error> {
-    const LEN: usize = 8;
-    let mut buf = [0 as u8; LEN];
-    let mut sum = 0.0;
-    let mut min = f64::MAX;
-    let mut max = 0.0;
-    for i in 1..count {
-        let i_as_bytes = i.to_be_bytes();
-        buf[(LEN - i_as_bytes.len())..LEN].copy_from_slice(&i_as_bytes);
-        buf[0] = PING_CMD;
-
-        let ping = J1939Packet::new_packet(0x18, pgn, dest, address, &buf);
-        let mut stream = rp1210.bus.iter_for(Duration::from_secs(2));
-        let echo = rp1210.send(&ping)?;
-        match stream.find(|p| p.source() == dest && p.pgn() == pgn && p.data()[0] == PING_CMD) {
-            Some(pong) => {
-                let time = pong.time() - echo.time();
-                sum += time;
-                if time < min {
-                    min = time;
-                }
-                if time > max {
-                    max = time;
+    window: usize,
+) -> Result<LatencyStats, Error> {
+    const TIMEOUT: Duration = Duration::from_secs(2);
+    control.require(CAP_PING)?;
+    let mut stats = LatencyStats::new();
+    let mut pongs = control.replies();
+    // seq -> echo of the request and when it was sent
+    let mut outstanding: HashMap<u32, (J1939Packet, Instant)> = HashMap::new();
+    let mut received: HashSet<u32> = HashSet::new();
+    let mut lost: HashSet<u32> = HashSet::new();
+    let mut highest = 0;
+    let mut next = 1;
+    loop {
+        while next <= count && outstanding.len() < window.max(1) {
+            let echo = control.send(0, &Message::Ping { seq: next })?;
+            outstanding.insert(next, (echo, Instant::now()));
+            next += 1;
+        }
+        let Some(oldest) = outstanding.values().map(|(_, sent)| *sent).min() else {
+            break;
+        };
+        let wait = (oldest + TIMEOUT).saturating_duration_since(Instant::now());
+        match pongs
+            .next_timeout(wait)
+            .map(|p| (Message::parse(p.data()), p))
+        {
+            Some((Ok((_, Message::Ping { seq })), pong)) => {
+                if let Some((echo, _)) = outstanding.remove(&seq) {
+                    let time = pong.time() - echo.time();
+                    stats.record(seq, time);
+                    if seq < highest {
+                        stats.record_out_of_order();
+                    }
+                    highest = highest.max(seq);
+                    received.insert(seq);
+                    if verbose {
+                        eprintln!("{:8.4}\t{} -> {}", time, echo, pong)
+                    }
+                } else if received.contains(&seq) {
+                    stats.record_duplicate();
+                    eprintln!("duplicate {}", pong);
+                } else if lost.contains(&seq) {
+                    stats.record_late();
+                    eprintln!("late {}", pong);
                 }
-                if verbose {
-                    eprintln!("{:8.4}\t{} -> {}", time, echo, pong)
+            }
+            Some(_) => {}
+            None => {
+                let now = Instant::now();
+                let expired: Vec<u32> = outstanding
+                    .iter()
+                    .filter(|(_, (_, sent))| now >= *sent + TIMEOUT)
+                    .map(|(seq, _)| *seq)
+                    .collect();
+                for seq in expired {
+                    let (echo, _) = outstanding.remove(&seq).unwrap();
+                    stats.record_lost(seq);
+                    lost.insert(seq);
+                    eprintln!("{} no response", echo);
                 }
             }
-            None => eprintln!("{} no response", echo),
         }
     }
-    println!(
-        "ping avg: {:8.4} max: {:8.4} min: {:8.4}",
-        sum / count as f64,
-        max,
-        min
-    );
-    Ok(())
+    print!("ping {}{}", stats, stats.histogram());
+    Ok(stats)
 }
 
-fn server(rp1210: &Rp1210, address: u8, pgn: u32) -> Result<(), Error> {
-    println!("SERVER: address: {:02X} pgn: {:04X}", address, pgn);
+/// Control messages to a server at address
+fn server_requests(rp1210: &Rp1210, address: u8, pgn: u32) -> MqIter<J1939Packet> {
     rp1210
         .bus
-        .iter()
-        .filter(|p| p.pgn() == pgn && p.source() != address)
-        .try_for_each(|p| -> Result<(), Error> {
-            match p.data()[0] {
-                PING_CMD => {
-                    println!("PING: {:02X} {}", p.source(), p);
-                    // pong
-                    rp1210.send(&J1939Packet::new_packet(
-                        0x18,
-                        pgn,
-                        p.source(),
-                        address,
-                        p.data(),
-                    ))?;
+        .subscribe(move |p: &J1939Packet| p.pgn() == pgn && p.source() != address)
+}
+
+/// Answer PING, HELLO and EXIT directly and hand RX, TX and DUPLEX to a session per client address,
+/// so several clients can run tests at once. requests is from server_requests(), taken before any client starts.
+fn server(
+    rp1210: &Rp1210,
+    address: u8,
+    pgn: u32,
+    requests: MqIter<J1939Packet>,
+) -> Result<(), Error> {
+    println!(
+        "SERVER: address: {:02X} pgn: {:04X} protocol version: {}",
+        address,
+        pgn,
+        control::VERSION
+    );
+    let reply = |client: u8, token: u8, message: &Message| {
+        server_reply(rp1210, address, pgn, client, token, message)
+    };
+    std::thread::scope(|scope| -> Result<(), Error> {
+        let mut sessions: HashMap<u8, Sender<(u8, Message, J1939Packet)>> = HashMap::new();
+        for p in requests {
+            let client = p.source();
+            let (token, message) = match Message::parse(p.data()) {
+                Ok(m) => m,
+                Err(code) => {
+                    println!("{}: {}", code, p);
+                    let (token, error) = Message::error_reply(p.data(), code);
+                    reply(client, token, &error)?;
+                    continue;
                 }
-                RX_CMD => {
-                    // receive sequence
-                    let count = u32::from_be_bytes(p.data()[4..8].try_into()?);
-                    println!("RX {} {}", count, p);
-                    rx(false, rp1210.bus.iter(), pgn, address, count)?;
+            };
+            match message {
+                Message::Ping { .. } => {
+                    println!("PING: {:02X} {}", client, p);
+                    // pong
+                    reply(client, token, &message)?;
                 }
-                TX_CMD => {
-                    // send sequence
-                    let count = u32::from_be_bytes(p.data()[4..8].try_into()?);
-                    println!("TX {} {}", count, p);
-                    tx(false, rp1210, pgn, address, p.source(), count)?;
+                Message::Hello { capabilities } => {
+                    println!("HELLO: {:02X} capabilities: {:08X}", client, capabilities);
+                    reply(
+                        client,
+                        token,
+                        &Message::Ack {
+                            value: CAPABILITIES,
+                        },
+                    )?;
                 }
-                DATA_CMD => {}
-                EXIT_CMD => {
-                    println!("EXIT: {:02X} {}", p.source(), p);
-                    std::process::exit(0);
+                Message::Rx(_) | Message::Tx(_) | Message::Duplex(_) => {
+                    let mut command = (token, message, p);
+                    // a session whose worker has ended is started again
+                    while let Err(SendError(c)) = sessions
+                        .entry(client)
+                        .or_insert_with(|| {
+                            let (tx, commands) = std::sync::mpsc::channel();
+                            scope.spawn(move || {
+                                println!("SESSION: {:02X} started", client);
+                                if let Err(e) = session(rp1210, address, pgn, client, commands) {
+                                    println!("SESSION: {:02X} {}", client, e);
+                                }
+                            });
+                            tx
+                        })
+                        .send(command)
+                    {
+                        sessions.remove(&client);
+                        command = c;
+                    }
                 }
-                _ => {
-                    println!("Unknown command: {}", p);
+                Message::Exit => {
+                    println!("EXIT: {:02X} {}", client, p);
+                    reply(client, token, &Message::Ack { value: 0 })?;
+                    break;
                 }
+                // replies and data for other clients
+                Message::Data { .. }
+                | Message::Summary(_)
+                | Message::Ack { .. }
+                | Message::Error { .. } => {}
             };
-            Ok(())
-        })?;
+        }
+        // sessions finish their current test before the scope returns
+        println!("SERVER: waiting for {} sessions", sessions.len());
+        Ok(())
+    })?;
     eprintln!("Server exited!");
     Ok(())
 }
 
-fn log(bus: &MultiQueue<J1939Packet>) {
+/// Send message to client in its session
+fn server_reply(
+    rp1210: &Rp1210,
+    address: u8,
+    pgn: u32,
+    client: u8,
+    token: u8,
+    message: &Message,
+) -> Result<J1939Packet, Error> {
+    rp1210.send(&J1939Packet::new_packet(
+        0x18,
+        pgn,
+        client,
+        address,
+        &message.encode(client, token),
+    ))
+}
+
+/// Run one client's RX, TX and DUPLEX commands in order until the server drops the sender
+fn session(
+    rp1210: &Rp1210,
+    address: u8,
+    pgn: u32,
+    client: u8,
+    commands: Receiver<(u8, Message, J1939Packet)>,
+) -> Result<(), Error> {
+    let reply =
+        |token: u8, message: &Message| server_reply(rp1210, address, pgn, client, token, message);
+    for (token, message, p) in commands {
+        let params = match &message {
+            Message::Rx(params) | Message::Tx(params) | Message::Duplex(params) => params,
+            _ => continue,
+        };
+        if let Err(code) = params.validate(pgn) {
+            println!("{:02X} {}: {:?} {}", client, code, params, p);
+            reply(
+                token,
+                &Message::Error {
+                    command: message.command(),
+                    code,
+                },
+            )?;
+            continue;
+        }
+        // subscribe before the ACK so no DATA frame is missed
+        // the client sets how long to wait, plus the time a transport message takes to arrive
+        let rx_packets = rp1210
+            .bus
+            .iter_filtered_idle(params.idle_timeout(), params.data_filter(pgn, client));
+        reply(token, &Message::Ack { value: 0 })?;
+        let print_tx = |report: &RateReport| {
+            println!(
+                "TX {:02X} {}\nTX {:02X} {}",
+                client,
+                RateReport::HEADER,
+                client,
+                report
+            );
+            if let Some(skew) = report.skew_line() {
+                println!("TX {:02X} {}", client, skew);
+            }
+        };
+        let summary = match &message {
+            Message::Rx(_) => {
+                println!("RX {:02X} {} {}", client, params.count, p);
+                Some(rx(false, rx_packets, params, client)?)
+            }
+            Message::Tx(_) => {
+                println!("TX {:02X} {} {}", client, params.count, p);
+                print_tx(&tx(false, rp1210, pgn, address, client, client, params)?);
+                None
+            }
+            _ => {
+                println!("DUPLEX {:02X} {} {}", client, params.count, p);
+                let (report, summary) = std::thread::scope(|scope| {
+                    let rx = scope.spawn(move || rx(false, rx_packets, params, client));
+                    let report = tx(false, rp1210, pgn, address, client, client, params);
+                    (report, rx.join())
+                });
+                print_tx(&report?);
+                Some(summary.map_err(|_| anyhow::anyhow!("rx panicked"))??)
+            }
+        };
+        if let Some(summary) = summary {
+            println!(
+                "RX {:02X} {}\nRX {:02X} {}",
+                client,
+                RateReport::HEADER,
+                client,
+                summary.report(params.rate)
+            );
+            println!("RX {:02X} {}", client, summary);
+            reply(token, &Message::Summary(summary))?;
+        }
+    }
+    Ok(())
+}
+
+/// Each server with each client from plan, in this process. Ctrl-C stops after the current pair.
+fn matrix(plan: &Plan, verbose: bool) -> Result<MatrixReport, Error> {
+    let running = Arc::new(AtomicBool::new(true));
+    stop_on_ctrl_c(running.clone())?;
+    let mut report = MatrixReport::default();
+    for (s, c) in plan.pairs() {
+        if !running.load(Relaxed) {
+            break;
+        }
+        let (server, client) = (&plan.adapters[s], &plan.adapters[c]);
+        eprintln!("{}   ==>>   {}", client.name(), server.name());
+        match matrix_pair(plan, s, c, verbose) {
+            Ok(outcomes) => outcomes
+                .into_iter()
+                .for_each(|(test, o)| report.add(&server.name(), &client.name(), &test, o)),
+            Err(e) => report.add(
+                &server.name(),
+                &client.name(),
+                "connect",
+                Outcome::Failed(e.to_string()),
+            ),
+        }
+    }
+    Ok(report)
+}
+
+/// Run a server on adapter s and the plan's tests from adapter c, then stop the server with EXIT
+fn matrix_pair(
+    plan: &Plan,
+    s: usize,
+    c: usize,
+    verbose: bool,
+) -> Result<Vec<(String, Outcome)>, Error> {
+    let open = |a: &AdapterPlan, address: u8| {
+        ConnectionDescriptor {
+            adapter: a.adapter.clone(),
+            device: a.device,
+            connection_string: a.connection_string.clone(),
+            address,
+            verbose,
+            ini_dir: plan.ini_dir.clone(),
+            ..Default::default()
+        }
+        // separate queues, or each adapter would see the other's echoes as traffic
+        .open(&MultiQueue::new())
+    };
+    let client_rp1210 = open(&plan.adapters[c], plan.client_address)?;
+    let mut server_rp1210 = open(&plan.adapters[s], plan.server_address)?;
+    let server_events = print_events(&server_rp1210);
+    // subscribed before the client starts, so its HELLO can't beat the server
+    let requests = server_requests(&server_rp1210, plan.server_address, plan.pgn);
+    let outcomes = std::thread::scope(|scope| {
+        let server_thread =
+            scope.spawn(|| server(&server_rp1210, plan.server_address, plan.pgn, requests));
+        let control = ControlClient::connect(
+            &client_rp1210,
+            plan.client_address,
+            plan.server_address,
+            plan.pgn,
+        );
+        let outcomes = control.map(|control| {
+            let outcomes = matrix_tests(plan, &control, verbose);
+            if let Err(e) = request_exit(&control) {
+                eprintln!("EXIT failed: {}", e);
+                server_rp1210.running.store(false, Relaxed);
+            }
+            outcomes
+        });
+        if outcomes.is_err() {
+            server_rp1210.running.store(false, Relaxed);
+        }
+        match server_thread.join() {
+            Ok(Err(e)) => eprintln!("server: {}", e),
+            Err(_) => eprintln!("server panicked"),
+            Ok(Ok(())) => {}
+        }
+        outcomes
+    });
+    server_rp1210.close();
+    server_events.join().unwrap();
+    outcomes
+}
+
+fn matrix_tests(plan: &Plan, control: &ControlClient, verbose: bool) -> Vec<(String, Outcome)> {
+    let params = TestParams {
+        count: plan.count,
+        rate: plan.rate,
+        ..Default::default()
+    };
+    let outcome = |r: Result<RateReport, Error>| {
+        r.map_or_else(|e| Outcome::Failed(e.to_string()), Outcome::Rate)
+    };
+    let mut rtn = Vec::new();
+    for test in &plan.tests {
+        match test {
+            TestKind::Ping => rtn.push((
+                "ping".to_string(),
+                ping(verbose, control, plan.count, 1)
+                    .map_or_else(|e| Outcome::Failed(e.to_string()), Outcome::Ping),
+            )),
+            TestKind::Tx => rtn.push((
+                "tx".to_string(),
+                outcome(tx_bandwidth(verbose, control, &params)),
+            )),
+            TestKind::Rx => rtn.push((
+                "rx".to_string(),
+                outcome(rx_bandwidth(verbose, control, &params)),
+            )),
+            TestKind::Duplex => match duplex(verbose, control, &params) {
+                Ok((tx, rx)) => {
+                    rtn.push(("duplex tx".to_string(), Outcome::Rate(tx)));
+                    rtn.push(("duplex rx".to_string(), Outcome::Rate(rx)));
+                }
+                Err(e) => rtn.push(("duplex".to_string(), Outcome::Failed(e.to_string()))),
+            },
+        }
+    }
+    rtn
+}
+
+/// Print adapter events (watchdog warnings, disconnect and reconnect) to stderr as they happen,
+/// until the adapter is closed
+fn print_events(rp1210: &Rp1210) -> JoinHandle<()> {
+    let events = rp1210.events.iter();
+    std::thread::spawn(move || events.for_each(|e| eprintln!("{}", e)))
+}
+
+fn log(rp1210: &Rp1210, wall_clock: bool) {
+    if rp1210.protocol == Protocol::CAN {
+        log_lines(
+            rp1210,
+            wall_clock,
+            rp1210
+                .can_bus
+                .iter()
+                .map(|f| (f.wall_time(), f.to_string())),
+        );
+    } else if rp1210.protocol == Protocol::J1708 {
+        let mut reassembler = j1587::Reassembler::default();
+        log_lines(
+            rp1210,
+            wall_clock,
+            rp1210.j1708_bus.iter().map(move |m| {
+                let mut line = m.to_string();
+                match j1587::parameters(m.data()) {
+                    Ok(parameters) => parameters.iter().for_each(|p| {
+                        line.push_str(&format!("\n    {}", p));
+                        match reassembler.add(m.mid(), p) {
+                            Ok(Some(p)) => line.push_str(&format!("\n    complete {}", p)),
+                            Ok(None) => {}
+                            Err(e) => line.push_str(&format!("\n    {}", e)),
+                        }
+                    }),
+                    Err(e) => line.push_str(&format!("\n    {}", e)),
+                }
+                (m.wall_time(), line)
+            }),
+        );
+    } else {
+        log_lines(
+            rp1210,
+            wall_clock,
+            rp1210.bus.iter().map(|p| {
+                let mut line = p.to_string();
+                if j1939_22::is_multi_pg(&p) {
+                    match j1939_22::parse(p.data()) {
+                        Ok(pgs) => pgs
+                            .iter()
+                            .for_each(|pg| line.push_str(&format!("\n    {}", pg))),
+                        Err(e) => line.push_str(&format!("\n    {}", e)),
+                    }
+                }
+                (p.wall_time(), line)
+            }),
+        );
+    }
+}
+
+fn log_lines(rp1210: &Rp1210, wall_clock: bool, lines: impl Iterator<Item = (SystemTime, String)>) {
     let mut count: u64 = 0;
     let mut start = SystemTime::now();
-    bus.iter().for_each(|p| {
-        println!("{}", p);
+    lines.for_each(|(time, line)| {
+        if wall_clock {
+            println!("{} {}", clock::format_system_time(time), line);
+        } else {
+            println!("{}", line);
+        }
         count += 1;
         let millis = start.elapsed().unwrap().as_millis();
         if millis > 10000 {
-            eprintln!("{} packet/s", 1000 * count / millis as u64);
+            match rp1210.clock_drift() {
+                Some(drift) => eprintln!(
+                    "{} packet/s clock drift: {:.1} ppm",
+                    1000 * count / millis as u64,
+                    drift
+                ),
+                None => eprintln!("{} packet/s", 1000 * count / millis as u64),
+            }
             start = SystemTime::now();
             count = 0;
         }
     });
 }
 
-fn list_adapters() -> Result<(), Error> {
-    println!();
-    for n in rp1210_parsing::list_all_products()? {
-        println!("{}", n)
+fn list_adapters(format: ListFormat, ini: &IniDirs) -> Result<(), Error> {
+    let products = ini.list_all_products()?;
+    match format {
+        ListFormat::Table => print!("{}", rp1210_parsing::format_table(&products)),
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(&products)?),
     }
     Ok(())
 }
 
-/// send sequence of RX, 0, 0, 0, seq:u32
+/// send params.count test frames in the session of client address, paced to params.rate frames/s
 fn tx(
     verbose: bool,
     rp1210: &Rp1210,
     pgn: u32,
     address: u8,
     dest: u8,
-    count: u32,
-) -> Result<J1939Packet, Error> {
-    let mut sent = J1939Packet::default();
+    session: u8,
+    params: &TestParams,
+) -> Result<RateReport, Error> {
+    let (count, rate) = (params.count, params.rate);
+    let pgn = params.pgn(pgn);
+    let mut frames = FrameTimes::default();
+    let mut pacer = rate.map(Pacer::new).transpose()?;
+    let mut report = RateReport {
+        target: rate,
+        frames: count,
+        ..Default::default()
+    };
     for seq in 0..count {
-        let data: Vec<u8> = [DATA_CMD, 0, 0, 0]
-            .into_iter()
-            .chain(seq.to_be_bytes().into_iter())
-            .collect();
-        sent = rp1210.send(&J1939Packet::new_packet(0x18, pgn, dest, address, &data))?;
-        if verbose {
-            println!("tx: {}", sent);
+        if !rp1210.running.load(Relaxed) {
+            break;
         }
+        let data = params.payload(session, seq);
+        if let Some(pacer) = pacer.as_mut() {
+            pacer.wait();
+        }
+        let start = Instant::now();
+        match rp1210.send(&J1939Packet::new_packet(0x18, pgn, dest, address, &data)) {
+            Ok(sent) => {
+                report
+                    .latency
+                    .record(seq, start.elapsed().as_secs_f64() * 1000.0);
+                report.received += 1;
+                let skew = frames.add(sent.time(), start);
+                report.skew.record(seq, skew);
+                if verbose {
+                    println!("tx: {}", sent);
+                }
+            }
+            Err(e) => {
+                report.latency.record_lost(seq);
+                eprintln!("tx {} failed: {}", seq, e);
+            }
+        }
+    }
+    report.time = frames.elapsed();
+    Ok(report)
+}
+
+/// adapter time of the first and last frame of a run, ms
+#[derive(Default)]
+struct FrameTimes {
+    /// adapter time and host send time of the first frame
+    first: Option<(f64, Instant)>,
+    last: f64,
+}
+
+impl FrameTimes {
+    /// Returns the echo skew of this frame, ms
+    fn add(&mut self, time: f64, sent: Instant) -> f64 {
+        let (first, host) = *self.first.get_or_insert((time, sent));
+        self.last = time;
+        (time - first) - sent.duration_since(host).as_secs_f64() * 1000.0
+    }
+    fn elapsed(&self) -> f64 {
+        self.first.map_or(0.0, |(first, _)| self.last - first)
     }
-    Ok(sent)
 }
 
-/// receive sequence of RX, 0, 0, 0, seq:u32
+/// receive params.count test frames in the session of client address session, verifying each payload
+///
+/// rx_packets should already be filtered to the sender's PGN and source address.
+/// Ends when every frame has been received or rx_packets ends (idle timeout).
 fn rx(
     verbose: bool,
     rx_packets: impl Iterator<Item = J1939Packet>,
-    pgn: u32,
-    source: u8,
+    params: &TestParams,
+    session: u8,
+) -> Result<RxSummary, Error> {
+    let mut tracker = SeqTracker::new(params.count);
+    let mut next = 0;
+    for p in rx_packets {
+        let Some((seq, intact)) = params.check(session, p.data(), next) else {
+            continue;
+        };
+        next = seq.wrapping_add(1);
+        if !intact {
+            tracker.add_corrupt();
+            println!("corrupt: {}", p);
+        }
+        if !tracker.add(seq, p.time()) || verbose {
+            println!("rx: {}", p);
+        }
+        if tracker.complete() {
+            break;
+        }
+    }
+    Ok(tracker.summary())
+}
+
+/// send sequence of DATA, 0, 0, 0, seq:u32 as raw CAN frames, paced to rate frames/s
+fn can_tx(
+    verbose: bool,
+    rp1210: &Rp1210,
+    id: u32,
     count: u32,
-) -> Result<J1939Packet, Error> {
-    let mut seq = 0;
-    rx_packets
-        .filter(|p| p.source() == source && p.pgn() == pgn && p.data()[0] == DATA_CMD)
-        .take(count as usize)
-        .map(|p| -> Result<J1939Packet, Error> {
-            let rx_seq = u32::from_be_bytes(p.data()[4..8].try_into()?);
-            if rx_seq != seq {
-                println!("Invalid seq. expected {} received {}", seq, rx_seq);
+    rate: Option<f64>,
+) -> Result<RateReport, Error> {
+    let mut data = [DATA_CMD, 0, 0, 0, 0, 0, 0, 0];
+    let mut frames = FrameTimes::default();
+    let mut pacer = rate.map(Pacer::new).transpose()?;
+    let mut report = RateReport {
+        target: rate,
+        frames: count,
+        ..Default::default()
+    };
+    for seq in 0..count {
+        if !rp1210.running.load(Relaxed) {
+            break;
+        }
+        data[4..8].copy_from_slice(&seq.to_be_bytes());
+        if let Some(pacer) = pacer.as_mut() {
+            pacer.wait();
+        }
+        let start = Instant::now();
+        match rp1210.send_can(&CanFrame::new(id, &data)) {
+            Ok(sent) => {
+                report
+                    .latency
+                    .record(seq, start.elapsed().as_secs_f64() * 1000.0);
+                report.received += 1;
+                let skew = frames.add(sent.time(), start);
+                report.skew.record(seq, skew);
+                if verbose {
+                    println!("tx: {}", sent);
+                }
             }
-            seq = rx_seq + 1;
-            if verbose {
-                println!("rx: {}", p);
+            Err(e) => {
+                report.latency.record_lost(seq);
+                eprintln!("tx {} failed: {}", seq, e);
             }
-            Ok(p)
-        })
-        .last()
-        .unwrap()
+        }
+    }
+    report.time = frames.elapsed();
+    Ok(report)
+}
+
+/// receive sequence of DATA, 0, 0, 0, seq:u32 as raw CAN frames from another instance running can_tx
+fn can_rx(
+    verbose: bool,
+    rp1210: &Rp1210,
+    id: u32,
+    count: u32,
+    idle: Duration,
+) -> Result<RxSummary, Error> {
+    eprintln!("waiting for {} frames on {:X}", count, id);
+    let mut tracker = SeqTracker::new(count);
+    // wait for the first frame as long as it takes, then until the sender goes idle
+    let mut frames = rp1210.can_bus.subscribe(move |f: &CanFrame| {
+        f.id() == id && !f.echo() && f.dlc() == 8 && f.data()[0] == DATA_CMD
+    });
+    let mut next = frames.next();
+    while let Some(f) = next {
+        let seq = u32::from_be_bytes(f.data()[4..8].try_into()?);
+        if !tracker.add(seq, f.time()) || verbose {
+            println!("rx: {}", f);
+        }
+        if tracker.complete() {
+            break;
+        }
+        next = frames.next_timeout(idle);
+    }
+    Ok(tracker.summary())
 }
@@ -0,0 +1,294 @@
+use anyhow::*;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use crate::stats::{LatencyStats, RateReport};
+
+/// Test plan for the matrix subcommand: every adapter is the server for every other adapter
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plan {
+    /// frames or pings per test
+    #[serde(default = "default_count")]
+    pub count: u32,
+    /// control PGN
+    #[serde(default = "default_pgn")]
+    pub pgn: u32,
+    #[serde(default = "default_server_address")]
+    pub server_address: u8,
+    #[serde(default = "default_client_address")]
+    pub client_address: u8,
+    /// frames/s for tx, rx and duplex, as fast as possible when missing
+    pub rate: Option<f64>,
+    #[serde(default = "default_tests")]
+    pub tests: Vec<TestKind>,
+    /// directory with RP121032.ini/RP121064.ini and the vendor INIs
+    pub ini_dir: Option<PathBuf>,
+    #[serde(rename = "adapter")]
+    pub adapters: Vec<AdapterPlan>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdapterPlan {
+    /// name in the report, adapter:device when missing
+    pub name: Option<String>,
+    /// RP1210 Adapter Identifier
+    pub adapter: String,
+    /// RP1210 Device ID
+    pub device: u8,
+    #[serde(default = "default_connection_string")]
+    pub connection_string: String,
+    /// run a server on this adapter
+    #[serde(default = "yes")]
+    pub server: bool,
+    /// run clients on this adapter
+    #[serde(default = "yes")]
+    pub client: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestKind {
+    Ping,
+    Tx,
+    Rx,
+    Duplex,
+}
+
+fn default_count() -> u32 {
+    1000
+}
+fn default_pgn() -> u32 {
+    0xFFF1
+}
+fn default_server_address() -> u8 {
+    0xF9
+}
+fn default_client_address() -> u8 {
+    0xF8
+}
+fn default_tests() -> Vec<TestKind> {
+    vec![TestKind::Ping, TestKind::Tx, TestKind::Rx]
+}
+fn default_connection_string() -> String {
+    "J1939:Baud=Auto".to_string()
+}
+fn yes() -> bool {
+    true
+}
+
+impl AdapterPlan {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("{}:{}", self.adapter, self.device))
+    }
+}
+
+impl Plan {
+    pub fn parse(toml: &str) -> Result<Plan> {
+        let plan: Plan = toml::from_str(toml)?;
+        if plan.server_address == plan.client_address {
+            bail!(
+                "server_address and client_address are both {:02X}",
+                plan.server_address
+            );
+        }
+        if plan.pairs().is_empty() {
+            bail!("Test plan needs a server and a client on different adapters");
+        }
+        Ok(plan)
+    }
+
+    pub fn load(path: &Path) -> Result<Plan> {
+        Plan::parse(&std::fs::read_to_string(path)?)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    /// (server, client) indexes into adapters, servers in plan order
+    pub fn pairs(&self) -> Vec<(usize, usize)> {
+        let mut rtn = Vec::new();
+        for (s, _) in self.adapters.iter().enumerate().filter(|(_, a)| a.server) {
+            for (c, _) in self.adapters.iter().enumerate().filter(|(_, a)| a.client) {
+                if c != s {
+                    rtn.push((s, c));
+                }
+            }
+        }
+        rtn
+    }
+}
+
+/// Result of one test between a server and client
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Ping(LatencyStats),
+    Rate(RateReport),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub server: String,
+    pub client: String,
+    pub test: String,
+    pub outcome: Outcome,
+}
+
+/// Consolidated comparison of every pair and test
+#[derive(Debug, Clone, Default)]
+pub struct MatrixReport {
+    pub rows: Vec<Row>,
+}
+
+impl MatrixReport {
+    pub fn add(&mut self, server: &str, client: &str, test: &str, outcome: Outcome) {
+        self.rows.push(Row {
+            server: server.to_string(),
+            client: client.to_string(),
+            test: test.to_string(),
+            outcome,
+        });
+    }
+
+    pub fn failures(&self) -> usize {
+        self.rows
+            .iter()
+            .filter(|r| matches!(r.outcome, Outcome::Failed(_)))
+            .count()
+    }
+}
+
+impl Display for MatrixReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let width = |name: fn(&Row) -> &String, header: &str| {
+            self.rows
+                .iter()
+                .map(|r| name(r).len())
+                .max()
+                .unwrap_or(0)
+                .max(header.len())
+        };
+        let (sw, cw, tw) = (
+            width(|r| &r.server, "server"),
+            width(|r| &r.client, "client"),
+            width(|r| &r.test, "test"),
+        );
+        write!(
+            f,
+            "{:<sw$} {:<cw$} {:<tw$} achieved/s  loss %  corrupt   p50 ms   p99 ms   max ms",
+            "server", "client", "test"
+        )?;
+        let ms = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.4}", v));
+        for r in &self.rows {
+            write!(f, "\n{:<sw$} {:<cw$} {:<tw$} ", r.server, r.client, r.test)?;
+            match &r.outcome {
+                Outcome::Ping(stats) => write!(
+                    f,
+                    "{:>10} {:>7.2} {:>8} {:>8} {:>8} {:>8}",
+                    "-",
+                    stats.loss_percent(),
+                    "-",
+                    ms(stats.percentile(50.0)),
+                    ms(stats.percentile(99.0)),
+                    ms(stats.max())
+                )?,
+                Outcome::Rate(report) => write!(
+                    f,
+                    "{:>10.1} {:>7.2} {:>8} {:>8} {:>8} {:>8}",
+                    report.achieved(),
+                    report.loss_percent(),
+                    report.corrupt,
+                    ms(report.latency.percentile(50.0)),
+                    ms(report.latency.percentile(99.0)),
+                    ms(report.latency.max())
+                )?,
+                Outcome::Failed(e) => write!(f, "FAILED: {}", e)?,
+            }
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan() {
+        let plan = Plan::parse(include_str!("../matrix.toml")).unwrap();
+        assert_eq!(3, plan.adapters.len());
+        assert_eq!(0xF9, plan.server_address);
+        assert_eq!("J1939:Baud=500", plan.adapters[1].connection_string);
+        // every adapter serves the other two
+        assert_eq!(
+            vec![(0, 1), (0, 2), (1, 0), (1, 2), (2, 0), (2, 1)],
+            plan.pairs()
+        );
+
+        let plan = Plan::parse(
+            r#"
+            count = 10
+            pgn = 0xFFF0
+            tests = ["ping", "duplex"]
+            [[adapter]]
+            adapter = "A"
+            device = 1
+            client = false
+            [[adapter]]
+            name = "b"
+            adapter = "B"
+            device = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(0xFFF0, plan.pgn);
+        assert_eq!(vec![TestKind::Ping, TestKind::Duplex], plan.tests);
+        assert_eq!(vec![(0, 1)], plan.pairs());
+        assert_eq!("A:1", plan.adapters[0].name());
+        assert_eq!("b", plan.adapters[1].name());
+
+        // one adapter can't test itself
+        assert!(Plan::parse("[[adapter]]\nadapter = \"A\"\ndevice = 1").is_err());
+        assert!(Plan::parse("tests = [\"bogus\"]").is_err());
+        assert!(Plan::parse("server_address = 0xF8\n[[adapter]]\nadapter = \"A\"\ndevice = 1\n[[adapter]]\nadapter = \"B\"\ndevice = 1").is_err());
+    }
+
+    #[test]
+    fn report() {
+        let mut report = MatrixReport::default();
+        let mut ping = LatencyStats::new();
+        ping.record(1, 0.5);
+        report.add("PEAK", "NEXIQ", "ping", Outcome::Ping(ping));
+        report.add(
+            "PEAK",
+            "NEXIQ",
+            "tx",
+            Outcome::Rate(RateReport {
+                frames: 10,
+                received: 10,
+                time: 9.0,
+                ..Default::default()
+            }),
+        );
+        report.add("NEXIQ", "PEAK", "hello", Outcome::Failed("No reply".into()));
+        assert_eq!(1, report.failures());
+        let text = report.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            "server client test  achieved/s  loss %  corrupt   p50 ms   p99 ms   max ms",
+            lines[0]
+        );
+        assert_eq!(
+            "PEAK   NEXIQ  ping           -    0.00        -   0.5000   0.5000   0.5000",
+            lines[1]
+        );
+        assert_eq!(
+            "PEAK   NEXIQ  tx        1000.0    0.00        0        -        -        -",
+            lines[2]
+        );
+        assert_eq!("NEXIQ  PEAK   hello FAILED: No reply", lines[3]);
+    }
+}
@@ -1,5 +1,6 @@
 use std::fmt::Display;
 use std::option::*;
+use std::sync::atomic::{AtomicBool, Ordering::Acquire, Ordering::Release};
 use std::sync::*;
 use std::thread;
 use std::thread::JoinHandle;
@@ -19,56 +20,154 @@ pub struct MultiQueue<T> {
     // shared head that always points to the empty Arc<RwLock>
     // Yes, this seems like overkill, but we need to clone multiqueues to easily use them in threads, so this make cloning work easily.
     head: Arc<RwLock<MqNode<T>>>,
+    // set by close()
+    closed: Arc<AtomicBool>,
+    // wakes async subscribers on push
+    #[cfg(feature = "tokio")]
+    notify: Arc<tokio::sync::Notify>,
+}
+
+/// Subscription filter. Items are only cloned into subscriptions whose filter matches.
+pub trait Filter<T>: Send + Sync + 'static {
+    fn matches(&self, item: &T) -> bool;
+}
+impl<T, F> Filter<T> for F
+where
+    F: Fn(&T) -> bool + Send + Sync + 'static,
+{
+    fn matches(&self, item: &T) -> bool {
+        self(item)
+    }
 }
 
 /// Iterator
-struct MqIter<T> {
+pub struct MqIter<T> {
     head: MqNode<T>,
     until: Instant,
+    /// if set, until is pushed out by this much after every item
+    idle: Option<Duration>,
+    filter: Box<dyn Filter<T>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl<T> MqIter<T>
+where
+    T: Clone + Sync + Send + 'static,
+{
+    /// next(), but give up after timeout. The iterator stays usable.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<T> {
+        let until = self.until;
+        self.until = until.min(Instant::now() + timeout);
+        let rtn = self.next();
+        self.until = until;
+        rtn
+    }
 }
 
 impl<T> Iterator for MqIter<T>
 where
-    T: Clone + Sync + Send,
+    T: Clone + Sync + Send + 'static,
 {
     type Item = T;
     fn next(&mut self) -> std::option::Option<<Self as std::iter::Iterator>::Item> {
-        let mut o = None;
-        while o.is_none() && Instant::now() < self.until {
-            thread::yield_now();
-            o = self
-                .head
-                .read()
-                .unwrap()
-                .as_ref()
-                .map(|i| (i.data.clone(), i.next.clone()));
+        while Instant::now() < self.until {
+            let o = self.head.read().unwrap().as_ref().map(|i| {
+                // only clone the data if this subscriber wants it
+                let data = if self.filter.matches(&i.data) {
+                    Some(i.data.clone())
+                } else {
+                    None
+                };
+                (data, i.next.clone())
+            });
+            match o {
+                Some((data, next)) => {
+                    self.head = next;
+                    if data.is_some() {
+                        if let Some(idle) = self.idle {
+                            self.until = Instant::now() + idle;
+                        }
+                        return data;
+                    }
+                }
+                // everything pushed before close has been returned, unless the last push landed after the read
+                None if self.closed.load(Acquire) => {
+                    if self.head.read().unwrap().is_none() {
+                        return None;
+                    }
+                }
+                None => thread::yield_now(),
+            }
         }
-        o.map(|clones| {
-            self.head = clones.1;
-            clones.0
-        })
+        None
     }
 }
 
 #[allow(dead_code)]
 impl<T> MultiQueue<T>
 where
-    T: Clone + Sync + Send,
+    T: Clone + Sync + Send + 'static,
 {
     pub fn new() -> MultiQueue<T> {
         MultiQueue {
             head: Arc::new(RwLock::new(Arc::new(RwLock::new(None)))),
+            closed: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "tokio")]
+            notify: Arc::new(tokio::sync::Notify::new()),
         }
     }
     pub fn iter_for(&self, duration: Duration) -> impl Iterator<Item = T> {
+        self.iter_filtered_for(duration, |_: &T| true)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> {
+        self.iter_for(Duration::from_secs(60 * 60 * 24))
+    }
+
+    /// iter_for() that only returns items matching filter
+    pub fn iter_filtered_for(
+        &self,
+        duration: Duration,
+        filter: impl Filter<T>,
+    ) -> impl Iterator<Item = T> {
         MqIter {
             head: self.head.read().unwrap().clone(),
             until: Instant::now() + duration,
+            idle: None,
+            filter: Box::new(filter),
+            closed: self.closed.clone(),
         }
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = T> {
-        self.iter_for(Duration::from_secs(60 * 60 * 24))
+    /// iter_filtered() that ends when no matching item arrives for the idle duration
+    pub fn iter_filtered_idle(
+        &self,
+        idle: Duration,
+        filter: impl Filter<T>,
+    ) -> impl Iterator<Item = T> {
+        MqIter {
+            head: self.head.read().unwrap().clone(),
+            until: Instant::now() + idle,
+            idle: Some(idle),
+            filter: Box::new(filter),
+            closed: self.closed.clone(),
+        }
+    }
+
+    /// iter() that only returns items matching filter
+    pub fn iter_filtered(&self, filter: impl Filter<T>) -> impl Iterator<Item = T> {
+        self.subscribe(filter)
+    }
+
+    /// iter_filtered() as a concrete MqIter, for next_timeout()
+    pub fn subscribe(&self, filter: impl Filter<T>) -> MqIter<T> {
+        MqIter {
+            head: self.head.read().unwrap().clone(),
+            until: Instant::now() + Duration::from_secs(60 * 60 * 24),
+            idle: None,
+            filter: Box::new(filter),
+            closed: self.closed.clone(),
+        }
     }
 
     pub fn push(&mut self, item: T) {
@@ -81,6 +180,77 @@ where
         });
         // update head to point to the new empty item.
         *head = empty;
+        drop(head);
+        #[cfg(feature = "tokio")]
+        self.notify.notify_waiters();
+    }
+
+    /// End all iterators and streams once they have returned the items already pushed
+    pub fn close(&self) {
+        self.closed.store(true, Release);
+        #[cfg(feature = "tokio")]
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Acquire)
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[allow(dead_code)]
+impl<T> MultiQueue<T>
+where
+    T: Clone + Sync + Send + 'static,
+{
+    /// Async equivalent of iter(). Returns items pushed after the stream is created.
+    pub fn stream(&self) -> impl futures_core::Stream<Item = T> + Send {
+        self.stream_filtered(|_: &T| true)
+    }
+
+    /// stream() that only returns items matching filter
+    pub fn stream_filtered(
+        &self,
+        filter: impl Filter<T>,
+    ) -> impl futures_core::Stream<Item = T> + Send {
+        let head = self.head.read().unwrap().clone();
+        let state = (
+            head,
+            self.notify.clone(),
+            Arc::new(filter),
+            self.closed.clone(),
+        );
+        futures_util::stream::unfold(state, |(mut head, notify, filter, closed)| async move {
+            let data = loop {
+                // register for notification before checking, so a push between the check and the await is not missed
+                let notified = notify.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                let next = head.read().unwrap().as_ref().map(|i| {
+                    let data = if filter.matches(&i.data) {
+                        Some(i.data.clone())
+                    } else {
+                        None
+                    };
+                    (data, i.next.clone())
+                });
+                match next {
+                    Some((data, next)) => {
+                        head = next;
+                        if let Some(data) = data {
+                            break data;
+                        }
+                    }
+                    None if closed.load(Acquire) => {
+                        if head.read().unwrap().is_none() {
+                            return None;
+                        }
+                    }
+                    None => notified.await,
+                }
+            };
+            Some((data, (head, notify, filter, closed)))
+        })
     }
 }
 
@@ -116,4 +286,95 @@ mod tests {
         assert_eq!("three", i.next().unwrap());
         assert_eq!(std::option::Option::None, i.next());
     }
+
+    #[test]
+    fn idle() {
+        let mut q: MultiQueue<u32> = MultiQueue::new();
+        let mut i = q.iter_filtered_idle(Duration::from_millis(200), |_: &u32| true);
+        let mut p = q.clone();
+        thread::spawn(move || {
+            for n in 0..3 {
+                thread::sleep(Duration::from_millis(100));
+                p.push(n);
+            }
+        });
+        // total time exceeds the idle timeout, but the gaps do not
+        assert_eq!(vec![0, 1, 2], i.by_ref().take(3).collect::<Vec<_>>());
+        q.push(3);
+        assert_eq!(Some(3), i.next());
+        assert_eq!(None, i.next());
+    }
+
+    #[test]
+    fn filtered() {
+        let mut q: MultiQueue<u32> = MultiQueue::new();
+        let mut i = q.iter_filtered_for(Duration::from_secs(1), |n: &u32| n.is_multiple_of(2));
+        (1..=5).for_each(|n| q.push(n));
+        assert_eq!(Some(2), i.next());
+        assert_eq!(Some(4), i.next());
+        assert_eq!(None, i.next());
+    }
+
+    #[test]
+    fn next_timeout() {
+        let mut q: MultiQueue<u32> = MultiQueue::new();
+        let mut i = q.subscribe(|n: &u32| *n > 1);
+        let start = Instant::now();
+        assert_eq!(None, i.next_timeout(Duration::from_millis(50)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // still subscribed after a timeout
+        (1..=3).for_each(|n| q.push(n));
+        assert_eq!(Some(2), i.next_timeout(Duration::from_millis(50)));
+        assert_eq!(Some(3), i.next());
+    }
+
+    #[test]
+    fn close() {
+        let mut q: MultiQueue<u32> = MultiQueue::new();
+        let i = q.iter();
+        let p = q.clone();
+        thread::spawn(move || {
+            (0..3).for_each(|n| q.push(n));
+            q.close();
+        });
+        // a day long iterator ends right after the last item
+        let start = Instant::now();
+        assert_eq!(vec![0, 1, 2], i.collect::<Vec<_>>());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(p.is_closed());
+        assert_eq!(None, p.iter().next());
+    }
+
+    #[test]
+    fn close_race() {
+        // the last push and close() land between an iterator's read and its closed check
+        for _ in 0..10_000 {
+            let mut q: MultiQueue<u32> = MultiQueue::new();
+            let i = q.iter();
+            let reader = thread::spawn(move || i.count());
+            q.push(0);
+            q.close();
+            assert_eq!(1, reader.join().unwrap());
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn stream() {
+        use futures_util::StreamExt;
+        let mut q: MultiQueue<&str> = MultiQueue::new();
+        q.push("one");
+        let s = q.stream();
+        tokio::pin!(s);
+        let mut p = q.clone();
+        std::thread::spawn(move || {
+            p.push("two");
+            p.push("three");
+        });
+        assert_eq!(Some("two"), s.next().await);
+        assert_eq!(Some("three"), s.next().await);
+        assert!(tokio::time::timeout(Duration::from_millis(100), s.next())
+            .await
+            .is_err());
+    }
 }
@@ -0,0 +1,141 @@
+use anyhow::*;
+use std::time::{Duration, Instant};
+
+use crate::j1939_21;
+
+/// Sleep is only accurate to a few ms on Windows, so the last part of each wait is spun
+const SPIN: Duration = Duration::from_millis(2);
+
+/// Default J1939 bit rate when the connection string does not give one (Baud=Auto)
+const DEFAULT_BITRATE: u32 = 250_000;
+
+/// Schedules sends at a fixed rate
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    period: Duration,
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    /// rate in frames per second
+    pub fn new(rate: f64) -> Result<Pacer> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            bail!("Invalid rate {}", rate);
+        }
+        Ok(Pacer {
+            period: Duration::from_secs_f64(1.0 / rate),
+            next: None,
+        })
+    }
+
+    /// Wait for the next send slot. The first call returns immediately.
+    ///
+    /// Slots are on a fixed schedule so sleep overshoot does not accumulate.
+    /// When the caller falls behind (saturation) the schedule restarts instead of bursting to catch up.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        let slot = match self.next {
+            Some(next) if next > now => {
+                let left = next - now;
+                if left > SPIN {
+                    std::thread::sleep(left - SPIN);
+                }
+                while Instant::now() < next {
+                    std::hint::spin_loop();
+                }
+                next
+            }
+            _ => now,
+        };
+        self.next = Some(slot + self.period);
+    }
+}
+
+/// Bits on the bus for a data frame, including the 3 bit interframe space but not bit stuffing
+pub fn frame_bits(dlc: usize, extended: bool) -> u32 {
+    // SOF, arbitration, control, CRC, ACK, EOF and IFS
+    let overhead = if extended { 67 } else { 47 };
+    overhead + 8 * dlc as u32
+}
+
+/// Bits on the bus for a length byte J1939 message. Longer than 8 bytes it is a BAM: a TP.CM and its TP.DT frames.
+/// RTS/CTS adds a CTS and an EndOfMsgAck, which are not counted.
+pub fn message_bits(length: usize) -> u32 {
+    if length <= 8 {
+        frame_bits(length, true)
+    } else {
+        (1 + j1939_21::packets(length) as u32) * frame_bits(8, true)
+    }
+}
+
+/// Messages per second of length byte messages that load the bus to percent
+pub fn bus_load_rate(percent: f64, bitrate: u32, length: usize) -> f64 {
+    bitrate as f64 * percent / 100.0 / message_bits(length) as f64
+}
+
+/// Nominal bit rate from an RP1210 connection string, e.g. "J1939:Baud=500"
+pub fn bitrate(connection_string: &str) -> u32 {
+    connection_string
+        .split([':', ','])
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("baud"))
+        .and_then(|(_, v)| v.trim().parse::<u32>().ok())
+        .map(|kbaud| kbaud * 1000)
+        .unwrap_or(DEFAULT_BITRATE)
+}
+
+/// Target rates for a test run: each --rate, then each --bus-load converted to a rate.
+/// One unpaced run (None) when neither is given.
+pub fn target_rates(
+    rates: &[f64],
+    bus_loads: &[f64],
+    connection_string: &str,
+    length: usize,
+) -> Vec<Option<f64>> {
+    let bitrate = bitrate(connection_string);
+    let rtn: Vec<Option<f64>> = rates
+        .iter()
+        .copied()
+        .chain(bus_loads.iter().map(|l| bus_load_rate(*l, bitrate, length)))
+        .map(Some)
+        .collect();
+    if rtn.is_empty() {
+        vec![None]
+    } else {
+        rtn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        assert_eq!(131, frame_bits(8, true));
+        assert_eq!(111, frame_bits(8, false));
+        assert_eq!(250_000, bitrate("J1939:Baud=Auto"));
+        assert_eq!(500_000, bitrate("J1939:Baud=500"));
+        assert_eq!(250_000, bitrate("CAN"));
+        // 50% of 250k is 954 frames/s
+        assert_eq!(954, bus_load_rate(50.0, 250_000, 8) as u32);
+        // 100 bytes is a TP.CM and 15 TP.DT frames
+        assert_eq!(16 * 131, message_bits(100));
+        assert_eq!(59, bus_load_rate(50.0, 250_000, 100) as u32);
+        assert_eq!(vec![None], target_rates(&[], &[], "J1939", 8));
+        let rates = target_rates(&[100.0], &[50.0], "J1939:Baud=500", 8);
+        assert_eq!(Some(100.0), rates[0]);
+        assert_eq!(1908, rates[1].unwrap() as u32);
+        assert!(Pacer::new(0.0).is_err());
+    }
+
+    #[test]
+    fn pacing() {
+        let mut pacer = Pacer::new(1000.0).unwrap();
+        let start = Instant::now();
+        (0..101).for_each(|_| pacer.wait());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(150), "{:?}", elapsed);
+    }
+}
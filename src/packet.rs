@@ -1,67 +1,171 @@
+use crate::clock::*;
+use crate::multiqueue::Filter;
+use crate::pattern::MAX_LENGTH;
 use std::fmt::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Default, Debug, Clone)]
-pub struct Packet {
-    pub data: Vec<u8>,
+/// RP1210 read buffer for the longest J1939 message: timestamp[4] echo[1] pgn[3] how_priority[1] sa[1] da[1] data
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub const PACKET_SIZE: usize = 11 + MAX_LENGTH;
+
+/// Packet payload. Classic CAN frames are stored inline, larger (transport) payloads share one allocation.
+#[derive(Debug, Clone)]
+enum Payload {
+    Inline { len: u8, data: [u8; 8] },
+    Shared(Arc<[u8]>),
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload::Inline {
+            len: 0,
+            data: [0; 8],
+        }
+    }
 }
 
+impl Payload {
+    fn new(data: &[u8]) -> Payload {
+        if data.len() <= 8 {
+            let mut buf = [0; 8];
+            buf[..data.len()].copy_from_slice(data);
+            Payload::Inline {
+                len: data.len() as u8,
+                data: buf,
+            }
+        } else {
+            Payload::Shared(data.into())
+        }
+    }
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Payload::Inline { len, data } => &data[..*len as usize],
+            Payload::Shared(data) => data,
+        }
+    }
+}
+
+/// Where a packet came from.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// Built locally to be sent. Not seen on the bus yet.
+    #[default]
+    Local,
+    /// Sent by this adapter and echoed back by the RP1210 driver.
+    Echo,
+    /// Received from another node on the bus.
+    Bus,
+}
+
+/// CAN FD frame flags
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdFlags {
+    /// bit rate switch: data phase sent at the data bit rate
+    pub brs: bool,
+    /// error state indicator: transmitter is error passive
+    pub esi: bool,
+}
+
+/// CAN FD payload lengths. The DLC is the index.
+pub const FD_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+/// smallest CAN FD DLC that holds len bytes
+pub fn fd_dlc(len: usize) -> Option<u8> {
+    FD_LENGTHS.iter().position(|&l| l >= len).map(|d| d as u8)
+}
+
+/// J1939 packet with the RP1210 header parsed up front.
 #[derive(Default, Debug, Clone)]
 pub struct J1939Packet {
-    pub packet: Packet,
-    pub tx: bool,
-    time_stamp_weight: f64,
+    /// microseconds. Adapter clock (extended to 64 bits) for received and echoed packets, host clock for local packets.
+    time: u64,
+    /// host wall clock, microseconds since UNIX_EPOCH
+    wall: u64,
+    priority: u8,
+    pgn: u32,
+    source: u8,
+    dest: u8,
+    origin: Origin,
+    /// None for classic CAN
+    fd: Option<FdFlags>,
+    payload: Payload,
 }
 
 impl Display for J1939Packet {
     fn fmt(&self, f: &mut Formatter) -> Result {
         write!(
             f,
-            "{:12.4} {} [{}] {}{}",
+            "{:12.4} {}{} [{}] {}{}",
             self.time(),
             self.header(),
+            match self.fd {
+                None => "",
+                Some(FdFlags {
+                    brs: false,
+                    esi: false,
+                }) => " FD",
+                Some(FdFlags {
+                    brs: true,
+                    esi: false,
+                }) => " FD BRS",
+                Some(FdFlags {
+                    brs: false,
+                    esi: true,
+                }) => " FD ESI",
+                Some(FdFlags {
+                    brs: true,
+                    esi: true,
+                }) => " FD BRS ESI",
+            },
             self.length(),
             self.data_str(),
             if self.echo() { " (TX)" } else { "" }
         )
     }
 }
-fn as_hex(data: &[u8]) -> String {
+pub fn as_hex(data: &[u8]) -> String {
     let mut s = String::new();
     for byte in data {
         write!(&mut s, " {:02X}", byte).expect("Unable to write");
     }
     s[1..].to_string()
 }
-impl Display for Packet {
-    fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(
-            f,
-            "{}",
-            self.data
-                .iter()
-                .fold(String::new(), |a, &n| a + &n.to_string() + ", ")
-        )
-    }
-}
-impl Packet {
-    #[allow(dead_code)]
-    pub fn new_rp1210(data: &[u8]) -> Packet {
-        Packet {
-            data: data.to_vec(),
-        }
-    }
+
+/// PDU1 PGNs carry a destination address in the low byte
+pub fn is_pdu1(pgn: u32) -> bool {
+    pgn & 0xFF00 < 0xF000
 }
+
 impl J1939Packet {
+    /// Parse an RP1210 J1939 read buffer:
+    /// timestamp[4] echo[1] pgn[3] how_priority[1] sa[1] da[1] data[..]
     #[allow(dead_code)]
-    pub fn new_rp1210(data: &[u8], time_stamp_weight: f64) -> J1939Packet {
+    pub fn new_rp1210(data: &[u8], clock: &mut AdapterClock) -> J1939Packet {
+        let time = clock.extend(u32::from_be_bytes(data[0..4].try_into().unwrap()));
+        let mut pgn = u32::from_le_bytes([data[5], data[6], data[7], 0]);
+        let dest = data[10];
+        if is_pdu1(pgn) {
+            pgn = (pgn & 0x3FF00) | dest as u32;
+        }
         J1939Packet {
-            packet: Packet::new_rp1210(data),
-            tx: false,
-            time_stamp_weight,
+            time,
+            wall: micros_since_epoch(clock.to_system_time(time).unwrap_or_else(SystemTime::now)),
+            priority: data[8] & 0x07,
+            pgn,
+            source: data[9],
+            dest: if is_pdu1(pgn) { dest } else { 0xFF },
+            origin: if data[4] != 0 {
+                Origin::Echo
+            } else {
+                Origin::Bus
+            },
+            fd: None,
+            payload: Payload::new(&data[11..]),
         }
     }
     pub fn length(&self) -> usize {
-        self.packet.data.len() - 6 - self.offset()
+        self.payload.as_slice().len()
     }
 
     pub fn new_packet(priority: u8, pgn: u32, da: u8, sa: u8, data: &[u8]) -> J1939Packet {
@@ -74,60 +178,83 @@ impl J1939Packet {
             data,
         )
     }
+    /// Build a packet to send from a 29 bit CAN ID
     #[allow(dead_code)]
     pub fn new(head: u32, data: &[u8]) -> J1939Packet {
-        let pgn = 0xFFFF & (head >> 8);
-        let da = if pgn < 0xF000 { 0xFF & pgn } else { 0 } as u8;
-        let hb = head.to_be_bytes();
-        let buf = [&[hb[2], hb[1], hb[0] & 0x3, hb[0] >> 2, hb[3], da], data].concat();
+        let pgn = 0x3FFFF & (head >> 8);
         J1939Packet {
-            packet: Packet::new_rp1210(&buf),
-            tx: true,
-            time_stamp_weight: 0.0,
+            time: host_time(),
+            wall: micros_since_epoch(SystemTime::now()),
+            priority: ((head >> 26) & 0x07) as u8,
+            pgn,
+            source: head as u8,
+            dest: if is_pdu1(pgn) { pgn as u8 } else { 0xFF },
+            origin: Origin::Local,
+            fd: None,
+            payload: Payload::new(data),
         }
     }
+
+    /// Mark as a CAN FD frame
+    #[allow(dead_code)]
+    pub fn with_fd(mut self, flags: FdFlags) -> J1939Packet {
+        self.fd = Some(flags);
+        self
+    }
+
+    /// CAN FD flags, None for classic CAN
+    #[allow(dead_code)]
+    pub fn fd(&self) -> Option<FdFlags> {
+        self.fd
+    }
+
+    /// Write the RP1210 J1939 send buffer:
+    /// pgn[3] how_priority[1] sa[1] da[1] data[..]
+    /// Returns the number of bytes written.
+    #[allow(dead_code)]
+    pub fn to_rp1210(&self, buf: &mut [u8]) -> usize {
+        let data = self.data();
+        buf[0..3].copy_from_slice(&self.pgn.to_le_bytes()[0..3]);
+        buf[3] = self.priority;
+        buf[4] = self.source;
+        buf[5] = if is_pdu1(self.pgn) { self.dest } else { 0 };
+        buf[6..6 + data.len()].copy_from_slice(data);
+        6 + data.len()
+    }
+
+    /// milliseconds. Adapter clock for received and echoed packets, host clock for local packets.
     pub fn time(&self) -> f64 {
-        if self.tx {
-            0.0
-        } else {
-            // FIXME mask is probably not necessary
-            ((0xFF000000 & (self.packet.data[0] as u64) << 24)
-                | (0xFF0000 & (self.packet.data[1] as u64) << 16)
-                | (0xFF00 & (self.packet.data[2] as u64) << 8)
-                | (0xFF & (self.packet.data[3] as u64))) as f64
-                * 0.001
-                * self.time_stamp_weight
-        }
+        self.time as f64 * 0.001
     }
 
-    /// offset into array for common data (tx and not tx)
-    fn offset(&self) -> usize {
-        if self.tx {
-            0
-        } else {
-            5
-        }
+    /// host wall clock time the packet was received (or built)
+    pub fn wall_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.wall)
     }
 
+    #[allow(dead_code)]
+    pub fn origin(&self) -> Origin {
+        self.origin
+    }
+
+    /// true for packets sent by this adapter
     pub fn echo(&self) -> bool {
-        self.tx || self.packet.data[4] != 0
+        self.origin != Origin::Bus
     }
 
     pub fn source(&self) -> u8 {
-        self.packet.data[4 + self.offset()]
+        self.source
     }
+    /// PGN. For PDU1 this includes the destination address.
     pub fn pgn(&self) -> u32 {
-        let mut pgn = ((self.packet.data[2 + self.offset()] as u32 & 0xFF) << 16)
-            | ((self.packet.data[1 + self.offset()] as u32 & 0xFF) << 8)
-            | (self.packet.data[self.offset()] as u32 & 0xFF);
-        if pgn < 0xF000 {
-            let destination = self.packet.data[5 + self.offset()] as u32;
-            pgn |= destination;
-        }
-        pgn
+        self.pgn
+    }
+    /// destination address for PDU1 packets, 0xFF (global) for PDU2
+    pub fn dest(&self) -> u8 {
+        self.dest
     }
     pub fn priority(&self) -> u8 {
-        self.packet.data[3 + self.offset()] & 0x07
+        self.priority
     }
     pub fn header(&self) -> String {
         format!(
@@ -137,13 +264,54 @@ impl J1939Packet {
         )
     }
     pub fn data_str(&self) -> String {
-        as_hex(&self.data())
+        as_hex(self.data())
     }
 
     pub fn data(&self) -> &[u8] {
-        &self.packet.data[self.offset() + 6..]
+        self.payload.as_slice()
+    }
+}
+
+/// Subscription filter on J1939 header fields. Unset fields match any packet.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct J1939Filter {
+    pgn: Option<(u32, u32)>,
+    source: Option<u8>,
+    dest: Option<u8>,
+}
+
+#[allow(dead_code)]
+impl J1939Filter {
+    pub fn new() -> J1939Filter {
+        J1939Filter::default()
+    }
+    /// match PGN exactly (for PDU1, pgn() includes the destination address)
+    pub fn pgn(self, pgn: u32) -> J1939Filter {
+        self.pgn_mask(pgn, 0x3FFFF)
+    }
+    /// match PGN bits set in mask, i.e. pgn_mask(0xEF00, 0x3FF00) for PGN EF00 to any destination
+    pub fn pgn_mask(mut self, pgn: u32, mask: u32) -> J1939Filter {
+        self.pgn = Some((pgn & mask, mask));
+        self
+    }
+    pub fn source(mut self, source: u8) -> J1939Filter {
+        self.source = Some(source);
+        self
+    }
+    pub fn dest(mut self, dest: u8) -> J1939Filter {
+        self.dest = Some(dest);
+        self
+    }
+}
+
+impl Filter<J1939Packet> for J1939Filter {
+    fn matches(&self, p: &J1939Packet) -> bool {
+        self.pgn.is_none_or(|(pgn, mask)| p.pgn() & mask == pgn)
+            && self.source.is_none_or(|sa| p.source() == sa)
+            && self.dest.is_none_or(|da| p.dest() == da)
     }
 }
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -151,17 +319,93 @@ mod tests {
 
     #[test]
     fn test_j1939packet_display() {
+        // local packets are stamped with host time, so skip the time column
+        assert_eq!(
+            " 18FFAAFA [3] 01 02 03 (TX)",
+            &J1939Packet::new(0x18FFAAFA, &[1, 2, 3]).to_string()[12..]
+        );
+        assert_eq!(
+            " 18FFAAF9 [8] 01 02 03 04 05 06 07 08 (TX)",
+            &J1939Packet::new(0x18FFAAF9, &[1, 2, 3, 4, 5, 6, 7, 8]).to_string()[12..]
+        );
         assert_eq!(
-            "      0.0000 18FFAAFA [3] 01 02 03 (TX)",
-            J1939Packet::new(0x18FFAAFA, &[1, 2, 3]).to_string()
+            " 18FFAAFB [8] FF 00 FF 00 FF 00 FF 00 (TX)",
+            &J1939Packet::new(0x18FFAAFB, &[0xFF, 00, 0xFF, 00, 0xFF, 00, 0xFF, 00]).to_string()
+                [12..]
         );
+    }
+
+    #[test]
+    fn test_rp1210_round_trip() {
+        let p = J1939Packet::new_packet(0x18, 0xEF00, 0x25, 0xF9, &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let mut buf = [0; 64];
+        let len = p.to_rp1210(&mut buf);
+        assert_eq!(&[0x25, 0xEF, 0, 6, 0xF9, 0x25], &buf[0..6]);
+
+        // read buffer adds timestamp and echo
+        let read = [&[0, 0, 0x03, 0xE8, 1], &buf[0..len]].concat();
+        let r = J1939Packet::new_rp1210(&read, &mut AdapterClock::new(1000.0));
+        assert_eq!(1000.0, r.time());
+        assert_eq!(Origin::Echo, r.origin());
+        assert_eq!(p.pgn(), r.pgn());
+        assert_eq!(p.source(), r.source());
+        assert_eq!(p.dest(), r.dest());
+        assert_eq!(p.priority(), r.priority());
+        assert_eq!(p.data(), r.data());
         assert_eq!(
-            "      0.0000 18FFAAF9 [8] 01 02 03 04 05 06 07 08 (TX)",
-            J1939Packet::new(0x18FFAAF9, &[1, 2, 3, 4, 5, 6, 7, 8]).to_string()
+            "   1000.0000 18EF25F9 [9] 01 02 03 04 05 06 07 08 09 (TX)",
+            r.to_string()
         );
+    }
+
+    #[test]
+    fn max_length() {
+        let data = [0x55; MAX_LENGTH];
+        let p = J1939Packet::new_packet(0x18, 0xEF00, 0x25, 0xF9, &data);
+        let mut buf = [0; PACKET_SIZE];
+        let len = p.to_rp1210(&mut buf);
+        assert_eq!(6 + MAX_LENGTH, len);
+
+        // the read buffer's timestamp and echo fill the rest
+        let read = [&[0, 0, 0, 0, 1], &buf[0..len]].concat();
+        assert_eq!(PACKET_SIZE, read.len());
+        let r = J1939Packet::new_rp1210(&read, &mut AdapterClock::new(1000.0));
+        assert_eq!(&data[..], r.data());
+    }
+
+    #[test]
+    fn test_fd() {
+        assert_eq!(Some(8), fd_dlc(8));
+        assert_eq!(Some(9), fd_dlc(9));
+        assert_eq!(Some(15), fd_dlc(64));
+        assert_eq!(None, fd_dlc(65));
+        let p = J1939Packet::new(0x18FFAAFA, &[0; 12]).with_fd(FdFlags {
+            brs: true,
+            esi: false,
+        });
+        assert_eq!(12, p.length());
         assert_eq!(
-            "      0.0000 18FFAAFB [8] FF 00 FF 00 FF 00 FF 00 (TX)",
-            J1939Packet::new(0x18FFAAFB, &[0xFF, 00, 0xFF, 00, 0xFF, 00, 0xFF, 00]).to_string()
+            " 18FFAAFA FD BRS [12] 00 00 00 00 00 00 00 00 00 00 00 00 (TX)",
+            &p.to_string()[12..]
         );
     }
+
+    #[test]
+    fn test_j1939filter() {
+        let p = J1939Packet::new_packet(0x18, 0xEF00, 0x25, 0xF9, &[1, 2, 3]);
+        assert_eq!(0x25, p.dest());
+        assert!(J1939Filter::new().matches(&p));
+        assert!(J1939Filter::new().pgn(0xEF25).source(0xF9).matches(&p));
+        assert!(J1939Filter::new()
+            .pgn_mask(0xEF00, 0x3FF00)
+            .dest(0x25)
+            .matches(&p));
+        assert!(!J1939Filter::new().pgn(0xEF00).matches(&p));
+        assert!(!J1939Filter::new().source(0xF8).matches(&p));
+
+        let p = J1939Packet::new_packet(0x18, 0xFFF1, 0x25, 0xF9, &[1, 2, 3]);
+        assert_eq!(0xFF, p.dest());
+        assert_eq!(6, p.priority());
+        assert!(J1939Filter::new().pgn(0xFFF1).dest(0xFF).matches(&p));
+    }
 }
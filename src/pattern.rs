@@ -0,0 +1,150 @@
+/// Largest J1939 transport protocol message
+pub const MAX_LENGTH: usize = 1785;
+
+/// Frames past the expected one searched for a Random payload, so a lost frame does not
+/// make every later frame look corrupt
+const RESYNC: u32 = 64;
+
+/// Bandwidth test payload
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pattern {
+    /// DATA control message with the sequence number, padded with zeros
+    #[default]
+    Sequence,
+    Zeros,
+    Ones,
+    /// 0x55
+    Alternating,
+    /// xorshift of the seed and sequence number
+    Random,
+    /// runs of four equal bits, so the CAN controller stuffs as many bits as it can
+    StuffWorst,
+}
+
+impl From<Pattern> for u8 {
+    fn from(pattern: Pattern) -> Self {
+        pattern as u8
+    }
+}
+
+impl TryFrom<u8> for Pattern {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Ok(match value {
+            0 => Pattern::Sequence,
+            1 => Pattern::Zeros,
+            2 => Pattern::Ones,
+            3 => Pattern::Alternating,
+            4 => Pattern::Random,
+            5 => Pattern::StuffWorst,
+            v => return Err(v),
+        })
+    }
+}
+
+impl Pattern {
+    /// Payload of frame seq. Not for Sequence, which is built by the control protocol.
+    pub fn fill(&self, seed: u32, seq: u32, buf: &mut [u8]) {
+        match self {
+            Pattern::Sequence | Pattern::Zeros => buf.fill(0),
+            Pattern::Ones => buf.fill(0xFF),
+            Pattern::Alternating => buf.fill(0x55),
+            Pattern::Random => {
+                // xorshift must not start at 0
+                let mut state =
+                    (seed.wrapping_mul(0x85EB_CA6B) ^ seq.wrapping_mul(0x9E37_79B9)).max(1);
+                for b in buf.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    *b = state as u8;
+                }
+            }
+            Pattern::StuffWorst => {
+                // 00000 then 1111 0000 repeated: a stuff bit after the first five, then every four
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = if i == 0 { 0x07 } else { 0x87 };
+                }
+            }
+        }
+    }
+
+    pub fn payload(&self, seed: u32, seq: u32, length: usize) -> Vec<u8> {
+        let mut rtn = vec![0; length];
+        self.fill(seed, seq, &mut rtn);
+        rtn
+    }
+
+    /// Sequence number of a received payload, expected next. None when it matches no frame.
+    /// Only Random payloads differ per frame; the others are numbered in order of arrival.
+    pub fn find(&self, seed: u32, data: &[u8], next: u32) -> Option<u32> {
+        let tries = if *self == Pattern::Random { RESYNC } else { 1 };
+        (next..next.saturating_add(tries)).find(|seq| self.payload(seed, *seq, data.len()) == data)
+    }
+}
+
+/// Stuff bits the CAN controller adds to data, counted from the start of data
+pub fn stuff_bits(data: &[u8]) -> u32 {
+    let mut count = 0;
+    let mut run = 0;
+    let mut last = None;
+    let bits = data
+        .iter()
+        .flat_map(|b| (0..8).rev().map(move |i| (b >> i) & 1 == 1));
+    for bit in bits {
+        if Some(bit) == last {
+            run += 1;
+        } else {
+            last = Some(bit);
+            run = 1;
+        }
+        if run == 5 {
+            // the stuff bit starts a run of the other value
+            count += 1;
+            last = Some(!bit);
+            run = 1;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert_eq!(vec![0xFF; 3], Pattern::Ones.payload(0, 7, 3));
+        assert_eq!(vec![0x55; 8], Pattern::Alternating.payload(0, 7, 8));
+        assert!(Pattern::Zeros.payload(0, 0, 0).is_empty());
+        let random = Pattern::Random.payload(42, 7, 8);
+        assert_eq!(random, Pattern::Random.payload(42, 7, 8));
+        assert_ne!(random, Pattern::Random.payload(42, 8, 8));
+        assert_ne!(random, Pattern::Random.payload(43, 7, 8));
+        for p in [0, 1, 2, 3, 4, 5] {
+            assert_eq!(p, u8::from(Pattern::try_from(p).unwrap()));
+        }
+        assert_eq!(Err(6), Pattern::try_from(6));
+    }
+
+    #[test]
+    fn find() {
+        let random = Pattern::Random.payload(1, 10, 8);
+        // frames 5 to 9 lost
+        assert_eq!(Some(10), Pattern::Random.find(1, &random, 5));
+        assert_eq!(None, Pattern::Random.find(1, &random, 11));
+        assert_eq!(None, Pattern::Random.find(1, &[0; 8], 0));
+        assert_eq!(Some(3), Pattern::Zeros.find(0, &[0; 8], 3));
+        assert_eq!(None, Pattern::Zeros.find(0, &[0, 1], 3));
+    }
+
+    #[test]
+    fn stuffing() {
+        assert_eq!(0, stuff_bits(&[0x0F; 8]));
+        assert_eq!(0, stuff_bits(&Pattern::Alternating.payload(0, 0, 8)));
+        // 64 equal bits, stuffed after every five
+        assert_eq!(12, stuff_bits(&[0; 8]));
+        assert_eq!(15, stuff_bits(&Pattern::StuffWorst.payload(0, 0, 8)));
+    }
+}
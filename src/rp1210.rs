@@ -1,24 +1,39 @@
+use crate::can::*;
+use crate::clock::*;
+use crate::command::*;
+use crate::health::*;
+use crate::j1708::*;
+use crate::j1939_21::transfer_time;
 use crate::multiqueue::*;
 use crate::packet::*;
-use crate::rp1210_parsing;
+use crate::rp1210_parsing::{IniDirs, Protocol};
 use anyhow::*;
 use libloading::os::windows::Symbol as WinSymbol;
 use libloading::*;
-use std::ffi::CString;
+use std::ffi::{c_char, CString};
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::atomic::*;
 use std::sync::*;
-use std::time::Duration;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-pub const PACKET_SIZE: usize = 1600;
+/// how often the watchdog polls RP1210_GetHardwareStatus
+const WATCHDOG_PERIOD: Duration = Duration::from_secs(5);
+/// how long close() keeps reading what the adapter already received
+const DRAIN_TIME: Duration = Duration::from_millis(250);
+/// how long a send waits for the adapter to echo a single frame
+const ECHO_TIMEOUT: Duration = Duration::from_secs(2);
 
-type ClientConnectType = unsafe extern "stdcall" fn(i32, i16, *const char, i32, i32, i16) -> i16;
-type SendType = unsafe extern "stdcall" fn(i16, *const u8, i16, i16, i16) -> i16;
-type ReadType = unsafe extern "stdcall" fn(i16, *const u8, i16, i16) -> i16;
-type CommandType = unsafe extern "stdcall" fn(u16, i16, *const u8, u16) -> i16;
-type _VERSION = unsafe extern "stdcall" fn(i16, *const u8, i16, i16) -> i16;
-type GetErrorType = unsafe extern "stdcall" fn(i16, *const u8) -> i16;
-type ClientDisconnectType = unsafe extern "stdcall" fn(i16) -> i16;
+// "system" is stdcall for 32 bit DLLs (RP121032.ini) and the x64 convention for 64 bit DLLs (RP121064.ini)
+// ClientConnect's first parameter is an HWND, which is pointer sized
+type ClientConnectType = unsafe extern "system" fn(isize, i16, *const c_char, i32, i32, i16) -> i16;
+type SendType = unsafe extern "system" fn(i16, *const u8, i16, i16, i16) -> i16;
+type ReadType = unsafe extern "system" fn(i16, *const u8, i16, i16) -> i16;
+type CommandType = unsafe extern "system" fn(u16, i16, *const u8, u16) -> i16;
+type ReadDetailedVersionType = unsafe extern "system" fn(i16, *mut u8, *mut u8, *mut u8) -> i16;
+type GetErrorType = unsafe extern "system" fn(i16, *const u8) -> i16;
+type ClientDisconnectType = unsafe extern "system" fn(i16) -> i16;
+type GetHardwareStatusType = unsafe extern "system" fn(i16, *mut u8, i16, i16) -> i16;
 
 fn log<F, T>(msg: &str, mut f: F) -> T
 where
@@ -32,15 +47,37 @@ where
 }
 pub struct Rp1210 {
     pub bus: MultiQueue<J1939Packet>,
-    api: API,
-    time_stamp_weight: f64,
+    /// frames read on a CAN protocol connection
+    pub can_bus: MultiQueue<CanFrame>,
+    /// messages read on a J1708 protocol connection
+    pub j1708_bus: MultiQueue<J1708Message>,
+    /// watchdog warnings
+    pub events: MultiQueue<Event>,
+    api: Arc<API>,
+    clock: Arc<Mutex<AdapterClock>>,
     pub running: Arc<AtomicBool>,
     pub id: String,
     pub device: i16,
     pub connection_string: String,
+    pub protocol: Protocol,
+    /// what the reader thread does after a fatal read error. Set before run().
+    pub reconnect: ReconnectPolicy,
+    /// reader and watchdog
+    threads: Vec<JoinHandle<()>>,
+}
+/// client_connect arguments, kept to connect again after the adapter goes away
+#[derive(Clone)]
+struct Connection {
+    device: i16,
+    connection_string: String,
+    address: u8,
+    app_packetize: bool,
 }
 struct API {
-    id: i16,
+    id: AtomicI16,
+    connection: Mutex<Option<Connection>>,
+    /// commands sent by the application, re-applied after a reconnect
+    settings: Mutex<Vec<Command>>,
 
     _lib: Library,
     client_connect_fn: WinSymbol<ClientConnectType>,
@@ -49,10 +86,13 @@ struct API {
     send_command_fn: WinSymbol<CommandType>,
     get_error_fn: WinSymbol<GetErrorType>,
     disconnect_fn: WinSymbol<ClientDisconnectType>,
+    get_hardware_status_fn: WinSymbol<GetHardwareStatusType>,
+    read_detailed_version_fn: WinSymbol<ReadDetailedVersionType>,
+    counters: Arc<Counters>,
 }
 impl Drop for API {
     fn drop(&mut self) {
-        unsafe { (*self.disconnect_fn)(self.id) };
+        unsafe { (*self.disconnect_fn)(self.id()) };
     }
 }
 impl API {
@@ -67,21 +107,64 @@ impl API {
             let get_error: Symbol<GetErrorType> = lib.get(b"RP1210_GetErrorMsg\0").unwrap();
             let disconnect: Symbol<ClientDisconnectType> =
                 lib.get(b"RP1210_ClientDisconnect\0").unwrap();
+            let get_hardware_status: Symbol<GetHardwareStatusType> =
+                lib.get(b"RP1210_GetHardwareStatus\0").unwrap();
+            let read_detailed_version: Symbol<ReadDetailedVersionType> =
+                lib.get(b"RP1210_ReadDetailedVersion\0").unwrap();
             API {
-                id: 0,
+                id: AtomicI16::new(0),
+                connection: Mutex::new(None),
+                settings: Mutex::new(Vec::new()),
                 client_connect_fn: client_connect.into_raw(),
                 send_fn: send.into_raw(),
                 read_fn: read.into_raw(),
                 send_command_fn: send_command.into_raw(),
                 get_error_fn: get_error.into_raw(),
                 disconnect_fn: disconnect.into_raw(),
+                get_hardware_status_fn: get_hardware_status.into_raw(),
+                read_detailed_version_fn: read_detailed_version.into_raw(),
+                counters: Arc::new(Counters::default()),
                 _lib: lib,
             }
         })
     }
-    fn send_command(&self, cmd: u16, buf: Vec<u8>) -> Result<i16> {
+    fn id(&self) -> i16 {
+        self.id.load(Relaxed)
+    }
+    fn send_command(&self, command: &Command) -> Result<i16> {
+        self.verify_return(unsafe {
+            (self.send_command_fn)(
+                command.number,
+                self.id(),
+                command.data.as_ptr(),
+                command.data.len() as u16,
+            )
+        })
+    }
+    fn get_hardware_status(&self) -> Result<HardwareStatus> {
+        read_hardware_status(*self.get_hardware_status_fn, self.id())
+    }
+    fn read_detailed_version(&self) -> Result<Versions> {
+        // 17 bytes each, NUL terminated
+        let mut api = [0; 17];
+        let mut dll = [0; 17];
+        let mut firmware = [0; 17];
         self.verify_return(unsafe {
-            (self.send_command_fn)(cmd, self.id, buf.as_ptr(), buf.len() as u16)
+            (self.read_detailed_version_fn)(
+                self.id(),
+                api.as_mut_ptr(),
+                dll.as_mut_ptr(),
+                firmware.as_mut_ptr(),
+            )
+        })?;
+        let string = |buf: &[u8]| {
+            let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..end]).trim().to_string()
+        };
+        Ok(Versions {
+            api: string(&api),
+            dll: string(&dll),
+            firmware: string(&firmware),
         })
     }
     fn get_error(&self, code: i16) -> Result<String> {
@@ -97,47 +180,146 @@ impl API {
         }
     }
     fn client_connect(
-        &mut self,
+        &self,
         dev_id: i16,
         connection_string: &str,
         address: u8,
         app_packetize: bool,
     ) -> Result<()> {
         let c_to_print = CString::new(connection_string).expect("CString::new failed");
-        self.id = self.verify_return(unsafe {
+        let id = self.verify_return(unsafe {
             (self.client_connect_fn)(
                 0,
                 dev_id,
-                c_to_print.as_ptr() as *const char,
+                c_to_print.as_ptr(),
                 0,
                 0,
                 if app_packetize { 1 } else { 0 },
             )
         })?;
-        if !app_packetize {
-            self.send_command(
-                /*CMD_PROTECT_J1939_ADDRESS*/ 19,
-                vec![
-                    address, 0, 0, 0xE0, 0xFF, 0, 0x81, 0, 0, /*CLAIM_BLOCK_UNTIL_DONE*/ 0,
-                ],
-            )?;
+        self.id.store(id, Relaxed);
+        *self.connection.lock().unwrap() = Some(Connection {
+            device: dev_id,
+            connection_string: connection_string.to_string(),
+            address,
+            app_packetize,
+        });
+        let j1939 = matches!(
+            Protocol::from_connection_string(connection_string),
+            Protocol::J1939 | Protocol::J1939FD
+        );
+        if j1939 && !app_packetize {
+            self.send_command(&ProtectAddress::new(address).block_until_done().build()?)?;
+        }
+        self.send_command(&Command::echo(true))?;
+        self.send_command(&Command::all_filters_pass())?;
+        Ok(())
+    }
+    /// Application command. Settings are remembered for reconnect.
+    fn command(&self, command: &Command) -> Result<i16> {
+        let rtn = self.send_command(command)?;
+        if command.persistent() {
+            self.settings.lock().unwrap().push(command.clone());
+        }
+        Ok(rtn)
+    }
+    /// Drop the old client, connect again and re-apply the address claim and settings
+    fn reconnect(&self) -> Result<()> {
+        unsafe { (self.disconnect_fn)(self.id()) };
+        let c = self
+            .connection
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow!("Never connected"))?;
+        self.client_connect(c.device, &c.connection_string, c.address, c.app_packetize)?;
+        for command in self.settings.lock().unwrap().iter() {
+            self.send_command(command)?;
         }
-        self.send_command(
-            /*CMD_ECHO_TRANSMITTED_MESSAGES*/ 16,
-            vec![/*ECHO_ON*/ 1],
-        )?;
-        self.send_command(/*CMD_SET_ALL_FILTERS_STATES_TO_PASS*/ 3, vec![])?;
         Ok(())
     }
     fn send(&self, packet: &J1939Packet) -> Result<i16> {
-        let buf = &packet.packet.data;
-        self.verify_return(unsafe { (self.send_fn)(self.id, buf.as_ptr(), buf.len() as i16, 0, 0) })
+        let mut buf = [0; PACKET_SIZE];
+        let len = packet.to_rp1210(&mut buf);
+        self.send_raw(&buf[..len])
+    }
+    /// send a message already in the RP1210 format for the connected protocol
+    fn send_raw(&self, buf: &[u8]) -> Result<i16> {
+        let rtn = unsafe { (self.send_fn)(self.id(), buf.as_ptr(), buf.len() as i16, 0, 0) };
+        if !(0..=127).contains(&rtn) {
+            self.counters.record(rtn.saturating_abs());
+        }
+        self.verify_return(rtn)
+    }
+}
+
+fn read_hardware_status(
+    get_hardware_status: GetHardwareStatusType,
+    id: i16,
+) -> Result<HardwareStatus> {
+    let mut buf = [0; HARDWARE_STATUS_SIZE];
+    let rtn = unsafe { get_hardware_status(id, buf.as_mut_ptr(), buf.len() as i16, 0) };
+    if !(0..=127).contains(&rtn) {
+        bail!("RP1210_GetHardwareStatus failed: {}", rtn);
+    }
+    HardwareStatus::parse(&buf)
+}
+
+/// Re-run client_connect until it works or the policy gives up. Returns true when connected.
+fn reconnect(
+    api: &API,
+    policy: &ReconnectPolicy,
+    running: &AtomicBool,
+    events: &mut MultiQueue<Event>,
+) -> bool {
+    let mut attempt = 0;
+    while running.load(Relaxed) {
+        attempt += 1;
+        sleep_while(running, policy.delay(attempt));
+        if !running.load(Relaxed) {
+            break;
+        }
+        events.push(Event::new(EventKind::Reconnecting(attempt)));
+        match api.reconnect() {
+            Result::Ok(()) => {
+                events.push(Event::new(EventKind::Reconnected));
+                return true;
+            }
+            Err(e) => {
+                eprintln!("reconnect attempt {} failed: {}", attempt, e);
+                if !policy.retry(attempt) {
+                    events.push(Event::new(EventKind::Warning(format!(
+                        "giving up after {} reconnect attempts",
+                        attempt
+                    ))));
+                    return false;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// A transport message is only echoed once the whole message has been sent
+fn echo_timeout(packet: &J1939Packet) -> Duration {
+    ECHO_TIMEOUT + transfer_time(packet.length())
+}
+
+/// sleep, waking early when running is cleared
+fn sleep_while(running: &AtomicBool, duration: Duration) {
+    let end = Instant::now() + duration;
+    while running.load(Relaxed) {
+        let now = Instant::now();
+        if now >= end {
+            break;
+        }
+        std::thread::sleep((end - now).min(Duration::from_millis(100)));
     }
 }
 
 impl Drop for Rp1210 {
     fn drop(&mut self) {
-        self.running.store(false, Relaxed)
+        self.close()
     }
 }
 
@@ -149,62 +331,262 @@ impl Rp1210 {
         connection_string: &str,
         address: u8,
         bus: MultiQueue<J1939Packet>,
+        ini: &IniDirs,
     ) -> Result<Rp1210> {
-        let mut api = API::new(id)?;
+        let api = API::new(id)?;
         api.client_connect(device, connection_string, address, false)?;
         Ok(Rp1210 {
-            api,
+            api: Arc::new(api),
             bus,
-            time_stamp_weight: rp1210_parsing::time_stamp_weight(id)?,
+            can_bus: MultiQueue::new(),
+            j1708_bus: MultiQueue::new(),
+            events: MultiQueue::new(),
+            clock: Arc::new(Mutex::new(AdapterClock::new(ini.time_stamp_weight(id)?))),
             running: Arc::new(AtomicBool::new(false)),
             id: id.to_string(),
             device,
             connection_string: connection_string.to_string(),
+            protocol: Protocol::from_connection_string(connection_string),
+            reconnect: ReconnectPolicy::default(),
+            threads: Vec::new(),
         })
     }
     /// background thread to read all packets into queue
     pub fn run(&mut self) {
-        let read = *self.api.read_fn;
-        let get_error_fn = *self.api.get_error_fn;
+        let api = self.api.clone();
+        let policy = self.reconnect.clone();
+        let mut events = self.events.clone();
         let running = self.running.clone();
-        let id = self.api.id;
         let mut bus = self.bus.clone();
-        let time_stamp_weight = self.time_stamp_weight;
+        let mut can_bus = self.can_bus.clone();
+        let mut j1708_bus = self.j1708_bus.clone();
+        let can = self.protocol == Protocol::CAN;
+        let j1708 = self.protocol == Protocol::J1708;
+        let clock = self.clock.clone();
+        // RP1210 does not report per frame BRS/ESI, so all frames on an FD connection are just marked FD
+        let fd = if self.protocol == Protocol::J1939FD {
+            Some(FdFlags::default())
+        } else {
+            None
+        };
         running.store(true, Relaxed);
         let driver = format!("{} {} {}", self.id, self.device, self.connection_string);
-        std::thread::spawn(move || {
+        let reader = std::thread::spawn(move || {
             let mut buf: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
-            while running.load(Relaxed) {
-                let size = unsafe { read(id, buf.as_mut_ptr(), PACKET_SIZE as i16, 0) };
-                if size > 0 {
-                    bus.push(J1939Packet::new_rp1210(
+            // set once close() is called. Keep reading until the adapter is empty or DRAIN_TIME passes.
+            let mut drain_until: Option<Instant> = None;
+            loop {
+                if !running.load(Relaxed) {
+                    let until = *drain_until.get_or_insert_with(|| Instant::now() + DRAIN_TIME);
+                    if Instant::now() > until {
+                        break;
+                    }
+                }
+                let size =
+                    unsafe { (api.read_fn)(api.id(), buf.as_mut_ptr(), PACKET_SIZE as i16, 0) };
+                if size > 0 && can {
+                    can_bus.push(CanFrame::new_rp1210(
+                        &buf[0..size as usize],
+                        &mut clock.lock().unwrap(),
+                    ))
+                } else if size > 0 && j1708 {
+                    j1708_bus.push(J1708Message::new_rp1210(
                         &buf[0..size as usize],
-                        time_stamp_weight,
+                        &mut clock.lock().unwrap(),
                     ))
+                } else if size > 0 {
+                    let packet =
+                        J1939Packet::new_rp1210(&buf[0..size as usize], &mut clock.lock().unwrap());
+                    bus.push(match fd {
+                        Some(flags) => packet.with_fd(flags),
+                        None => packet,
+                    })
+                } else if drain_until.is_some() {
+                    // drained, or the adapter is gone
+                    break;
                 } else {
                     if size < 0 {
                         // read error
                         let code = -size;
-                        let size = unsafe { (get_error_fn)(code, buf.as_mut_ptr()) } as usize;
-                        let msg = String::from_utf8_lossy(&buf[0..size]).to_string();
+                        api.counters.record(code);
+                        let msg = api.get_error(code).unwrap_or_default();
                         eprintln!("ERROR: {}: {}: {}", driver, code, msg,);
-                        std::thread::sleep(Duration::from_secs_f32(0.25))
+                        if is_fatal(code) && policy.enabled {
+                            events.push(Event::new(EventKind::Disconnected(format!(
+                                "{}: {}: {}",
+                                driver, code, msg
+                            ))));
+                            if reconnect(&api, &policy, &running, &mut events) {
+                                clock.lock().unwrap().restart();
+                            } else {
+                                running.store(false, Relaxed);
+                            }
+                        } else {
+                            std::thread::sleep(Duration::from_secs_f32(0.25))
+                        }
                     }
                     std::thread::yield_now();
                 }
             }
+            // end everything iterating the buses
+            bus.close();
+            can_bus.close();
+            j1708_bus.close();
         });
+        self.threads.push(reader);
+        let watchdog = self.watchdog();
+        self.threads.push(watchdog);
+    }
+
+    /// background thread to poll hardware status and publish warnings on events
+    fn watchdog(&self) -> JoinHandle<()> {
+        let api = self.api.clone();
+        let running = self.running.clone();
+        let mut events = self.events.clone();
+        let protocol = self.protocol.clone();
+        std::thread::spawn(move || {
+            let mut prev: Option<Health> = None;
+            let mut failing = false;
+            while running.load(Relaxed) {
+                match api.get_hardware_status() {
+                    Result::Ok(status) => {
+                        failing = false;
+                        let health = api.counters.health(status);
+                        if let Some(prev) = &prev {
+                            for warning in health.degradations(prev, &protocol) {
+                                events.push(Event::new(EventKind::Warning(warning)));
+                            }
+                        }
+                        prev = Some(health);
+                    }
+                    Err(e) if !failing => {
+                        failing = true;
+                        events.push(Event::new(EventKind::Warning(e.to_string())));
+                    }
+                    Err(_) => {}
+                }
+                sleep_while(&running, WATCHDOG_PERIOD);
+            }
+        })
+    }
+
+    /// Versions, hardware status and error counts
+    pub fn info(&self) -> Result<AdapterInfo> {
+        Ok(AdapterInfo {
+            versions: self.api.read_detailed_version()?,
+            protocol: self.protocol.clone(),
+            health: self.api.counters.health(self.api.get_hardware_status()?),
+        })
     }
 
     /// Send packet and return packet echoed back from adapter
     pub fn send(&self, packet: &J1939Packet) -> Result<J1939Packet> {
-        let mut stream = self.bus.iter_for(Duration::from_secs(2));
-        let send = self.api.send(packet);
-        // FIXME needs better error handling
-        send.map(|_| stream.find(move |p| p.data() == packet.data()).unwrap())
+        self.verify_fd(packet)?;
+        let mut stream = self.bus.iter_filtered_for(
+            echo_timeout(packet),
+            J1939Filter::new().pgn(packet.pgn()).source(packet.source()),
+        );
+        self.api.send(packet)?;
+        stream
+            .find(move |p| p.origin() == Origin::Echo && p.data() == packet.data())
+            .ok_or_else(|| anyhow!("No echo for {}", packet))
+    }
+
+    /// Send packet and asynchronously wait for the echo from the adapter
+    #[cfg(feature = "tokio")]
+    pub async fn send_async(&self, packet: &J1939Packet) -> Result<J1939Packet> {
+        self.verify_fd(packet)?;
+        use futures_util::StreamExt;
+        let stream = self
+            .bus
+            .stream_filtered(J1939Filter::new().pgn(packet.pgn()).source(packet.source()))
+            .filter(|p| {
+                std::future::ready(p.origin() == Origin::Echo && p.data() == packet.data())
+            });
+        tokio::pin!(stream);
+        // the DLL call itself is blocking, but returns as soon as the packet is queued
+        self.api.send(packet)?;
+        tokio::time::timeout(echo_timeout(packet), stream.next())
+            .await?
+            .ok_or_else(|| anyhow!("No echo for {}", packet))
     }
 
-    pub fn close(&self) {
-        self.running.store(false, Relaxed)
+    /// Send raw CAN frame and return frame echoed back from adapter
+    pub fn send_can(&self, frame: &CanFrame) -> Result<CanFrame> {
+        if self.protocol != Protocol::CAN {
+            bail!("CAN frame on {} connection: {}", self.protocol, frame);
+        }
+        if frame.is_rtr() {
+            bail!("RP1210 can't send remote frames: {}", frame);
+        }
+        let id = frame.id();
+        let mut stream = self
+            .can_bus
+            .iter_filtered_for(ECHO_TIMEOUT, move |f: &CanFrame| {
+                f.origin() == Origin::Echo && f.id() == id
+            });
+        let mut buf = [0; PACKET_SIZE];
+        let len = frame.to_rp1210(&mut buf);
+        self.api.send_raw(&buf[..len])?;
+        stream
+            .find(|f| f.data() == frame.data())
+            .ok_or_else(|| anyhow!("No echo for {}", frame))
+    }
+
+    /// Send J1708 message and return message echoed back from adapter
+    pub fn send_j1708(&self, message: &J1708Message) -> Result<J1708Message> {
+        if self.protocol != Protocol::J1708 {
+            bail!("J1708 message on {} connection: {}", self.protocol, message);
+        }
+        let mid = message.mid();
+        let mut stream = self
+            .j1708_bus
+            .iter_filtered_for(ECHO_TIMEOUT, move |m: &J1708Message| {
+                m.origin() == Origin::Echo && m.mid() == mid
+            });
+        let mut buf = [0; PACKET_SIZE];
+        let len = message.to_rp1210(&mut buf);
+        self.api.send_raw(&buf[..len])?;
+        stream
+            .find(|m| m.data() == message.data())
+            .ok_or_else(|| anyhow!("No echo for {}", message))
+    }
+
+    /// FD frames can only be sent on a J1939FD connection
+    fn verify_fd(&self, packet: &J1939Packet) -> Result<()> {
+        if packet.fd().is_some() && self.protocol != Protocol::J1939FD {
+            bail!("CAN FD packet on {} connection: {}", self.protocol, packet);
+        }
+        Ok(())
+    }
+
+    /// Send a command built with the command module builders
+    /// Filters and other settings are re-applied after a reconnect.
+    pub fn command(&self, command: &Command) -> Result<i16> {
+        self.api.command(command)
+    }
+
+    pub fn hardware_status(&self) -> Result<HardwareStatus> {
+        self.api.get_hardware_status()
+    }
+
+    /// Estimated adapter clock drift relative to the host, in parts per million
+    pub fn clock_drift(&self) -> Option<f64> {
+        self.clock.lock().unwrap().drift_ppm()
+    }
+
+    /// Stop reading, drain what the adapter already received and wait for the reader and watchdog.
+    /// The client is disconnected when the last thread using it is gone.
+    pub fn close(&mut self) {
+        self.running.store(false, Relaxed);
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                eprintln!("RP1210 thread panicked");
+            }
+        }
+        self.bus.close();
+        self.can_bus.close();
+        self.j1708_bus.close();
+        self.events.close();
     }
 }
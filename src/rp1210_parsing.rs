@@ -1,23 +1,65 @@
+use std::ffi::OsString;
 use std::fmt::Display;
+use std::path::PathBuf;
 
 use anyhow::*;
+use serde::{Serialize, Serializer};
 
-#[derive(Debug)]
+/// [DeviceInformationN] section of a vendor INI
+#[derive(Debug, Serialize)]
 pub struct Rp1210Dev {
     pub id: i16,
     pub name: String,
     pub description: String,
+    /// DeviceParams
+    pub params: String,
+    /// MultiCANChannels
+    pub can_channels: u8,
+    /// MultiJ1939Channels
+    pub j1939_channels: u8,
+    /// protocols that list this device
+    pub protocols: Vec<Rp1210Protocol>,
 }
-#[derive(Debug)]
+
+/// [ProtocolInformationN] section of a vendor INI
+#[derive(Debug, Clone, Serialize)]
+pub struct Rp1210Protocol {
+    pub protocol: Protocol,
+    pub description: String,
+    /// ProtocolSpeed, i.e. 250, 500, Auto
+    pub speeds: Vec<String>,
+    /// ProtocolParams
+    pub params: String,
+}
+
+/// [VendorInformation] section of a vendor INI
+#[derive(Debug, Serialize)]
 pub struct Rp1210Prod {
     pub id: String,
+    /// vendor name
     pub description: String,
+    pub version: String,
+    /// RP1210 API version, i.e. C
+    pub rp1210: String,
+    pub time_stamp_weight: f64,
+    pub auto_detect_capable: bool,
+    pub can_auto_baud: bool,
+    pub message_string: String,
+    pub can_formats_supported: String,
+    pub j1939_formats_supported: String,
     pub devices: Vec<Rp1210Dev>,
 }
 
 impl Display for Rp1210Dev {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {}:{}", self.id, self.name, self.description)
+        write!(f, "{} {}:{}", self.id, self.name, self.description)?;
+        for (i, p) in self.protocols.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " (" } else { "," }, p.protocol)?;
+        }
+        if !self.protocols.is_empty() {
+            write!(f, ")")?;
+        }
+        std::fmt::Result::Ok(())
     }
 }
 impl Display for Rp1210Prod {
@@ -30,83 +72,409 @@ impl Display for Rp1210Prod {
     }
 }
 
-pub fn list_all_products() -> Result<Vec<Rp1210Prod>> {
-    let start = std::time::Instant::now();
-    let load_from_file = ini::Ini::load_from_file("c:\\Windows\\RP121032.ini");
-    if load_from_file.is_err() {
-        // don't fail on linux
-        return Ok(Vec::new());
-    }
-    let rtn = Ok(load_from_file?
-        .get_from(Some("RP1210Support"), "APIImplementations")
-        .unwrap_or("")
-        .split(',')
-        .map(|s| {
-            let (description, devices) = list_devices_for_prod(s).unwrap();
-            Rp1210Prod {
-                id: s.to_string(),
-                description: description.to_string(),
-                devices,
+impl Rp1210Dev {
+    pub fn supports(&self, protocol: &Protocol) -> bool {
+        self.protocols.iter().any(|p| p.protocol == *protocol)
+    }
+}
+
+/// RP1210 protocol, the ProtocolString in the vendor INI and the prefix of a connection string
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Protocol {
+    J1939,
+    J1939FD,
+    CAN,
+    J1708,
+    ISO15765,
+    Other(String),
+}
+
+#[allow(dead_code)]
+impl Protocol {
+    /// protocol of a connection string like "J1939FD:Baud=500,2000"
+    pub fn from_connection_string(connection_string: &str) -> Protocol {
+        Protocol::from(connection_string.split(':').next().unwrap_or(""))
+    }
+}
+
+impl From<&str> for Protocol {
+    fn from(s: &str) -> Self {
+        match s.trim() {
+            "J1939" => Protocol::J1939,
+            "J1939FD" => Protocol::J1939FD,
+            "CAN" => Protocol::CAN,
+            "J1708" => Protocol::J1708,
+            "ISO15765" => Protocol::ISO15765,
+            other => Protocol::Other(other.to_string()),
+        }
+    }
+}
+
+impl Serialize for Protocol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Other(s) => write!(f, "{}", s),
+            p => write!(f, "{:?}", p),
+        }
+    }
+}
+
+/// protocols with their own column in the device table
+const TABLE_PROTOCOLS: [Protocol; 5] = [
+    Protocol::J1939,
+    Protocol::J1939FD,
+    Protocol::CAN,
+    Protocol::J1708,
+    Protocol::ISO15765,
+];
+
+/// Directories searched for RP121032.ini/RP121064.ini and the vendor INIs
+#[derive(Debug, Clone)]
+pub struct IniDirs {
+    dirs: Vec<PathBuf>,
+}
+
+/// Search order: --ini-dir, then RP1210_INI_DIR, then the platform defaults
+fn search_dirs(
+    explicit: Option<PathBuf>,
+    env: Option<OsString>,
+    home: Option<OsString>,
+) -> Vec<PathBuf> {
+    if let Some(dir) = explicit {
+        return vec![dir];
+    }
+    if let Some(dir) = env.filter(|d| !d.is_empty()) {
+        return vec![PathBuf::from(dir)];
+    }
+    if cfg!(windows) {
+        vec![PathBuf::from("C:\\Windows")]
+    } else {
+        let mut rtn: Vec<PathBuf> = home
+            .map(|h| PathBuf::from(h).join(".config/rp1210"))
+            .into_iter()
+            .collect();
+        rtn.push(PathBuf::from("/usr/local/etc/rp1210"));
+        rtn.push(PathBuf::from("/etc/rp1210"));
+        rtn
+    }
+}
+
+impl IniDirs {
+    pub fn new(ini_dir: Option<PathBuf>) -> IniDirs {
+        IniDirs {
+            dirs: search_dirs(
+                ini_dir,
+                std::env::var_os("RP1210_INI_DIR"),
+                std::env::var_os("HOME"),
+            ),
+        }
+    }
+
+    /// First file with this name, ignoring case, in the search path
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        self.dirs.iter().find_map(|dir| {
+            let exact = dir.join(name);
+            if exact.is_file() {
+                return Some(exact);
             }
+            // Linux file systems are case sensitive, vendor INI names are not
+            std::fs::read_dir(dir)
+                .ok()?
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .find(|p| {
+                    p.is_file()
+                        && p.file_name()
+                            .is_some_and(|f| f.to_string_lossy().eq_ignore_ascii_case(name))
+                })
         })
-        .collect());
-    println!("RP1210 INI parsing in {} ms", start.elapsed().as_millis());
-    rtn
+    }
+
+    /// RP121064.ini for 64 bit builds, RP121032.ini otherwise. Falls back to the other one.
+    fn load_main_ini(&self) -> Result<(PathBuf, ini::Ini)> {
+        let names = if cfg!(target_pointer_width = "64") {
+            ["RP121064.ini", "RP121032.ini"]
+        } else {
+            ["RP121032.ini", "RP121064.ini"]
+        };
+        let path = names
+            .iter()
+            .find_map(|n| self.find(n))
+            .ok_or_else(|| anyhow!("No {} found in {:?}", names.join(" or "), self.dirs))?;
+        let ini = ini::Ini::load_from_file(&path).with_context(|| format!("{:?}", path))?;
+        Ok((path, ini))
+    }
+
+    pub fn load_vendor_ini(&self, id: &str) -> Result<ini::Ini> {
+        let path = self
+            .find(&format!("{}.ini", id))
+            .ok_or_else(|| anyhow!("No {}.ini found in {:?}", id, self.dirs))?;
+        ini::Ini::load_from_file(&path).with_context(|| format!("{:?}", path))
+    }
+
+    pub fn list_all_products(&self) -> Result<Vec<Rp1210Prod>> {
+        let start = std::time::Instant::now();
+        let (path, main) = self.load_main_ini()?;
+        let rtn = main
+            .get_from(Some("RP1210Support"), "APIImplementations")
+            .unwrap_or("")
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| match self.list_devices_for_prod(s) {
+                Result::Ok(prod) => Some(prod),
+                Err(e) => {
+                    eprintln!("  {}.ini: {}", s, e);
+                    None
+                }
+            })
+            .collect();
+        eprintln!(
+            "RP1210 INI parsing ({:?}) in {} ms",
+            path,
+            start.elapsed().as_millis()
+        );
+        Ok(rtn)
+    }
+
+    fn list_devices_for_prod(&self, id: &str) -> Result<Rp1210Prod> {
+        let start = std::time::Instant::now();
+        let ini = self.load_vendor_ini(id)?;
+        let rtn = parse_vendor_ini(id, &ini);
+        eprintln!("  {}.ini parsing in {} ms", id, start.elapsed().as_millis());
+        rtn
+    }
+
+    #[allow(dead_code)]
+    pub fn time_stamp_weight(&self, id: &str) -> Result<f64> {
+        let ini = self.load_vendor_ini(id)?;
+        Ok(ini
+            .get_from_or::<&str>(Some("VendorInformation"), "TimeStampWeight", "1")
+            .trim()
+            .parse()?)
+    }
 }
 
-fn list_devices_for_prod(id: &str) -> Result<(String, Vec<Rp1210Dev>)> {
-    let start = std::time::Instant::now();
-    let ini = ini::Ini::load_from_file(&format!("c:\\Windows\\{}.ini", id))?;
+/// INI booleans are written TRUE/FALSE, Yes/No or 1/0
+fn parse_bool(value: Option<&str>) -> bool {
+    matches!(
+        value.map(|v| v.trim().to_ascii_uppercase()).as_deref(),
+        Some("TRUE" | "YES" | "1")
+    )
+}
 
-    // find device IDs for J1939
-    let j1939_devices: Vec<&str> = ini
+/// Build the product model from a vendor INI
+pub fn parse_vendor_ini(id: &str, ini: &ini::Ini) -> Result<Rp1210Prod> {
+    let vendor = ini
+        .section(Some("VendorInformation"))
+        .ok_or_else(|| anyhow!("missing [VendorInformation]"))?;
+    let get = |key: &str| vendor.get(key).unwrap_or("").trim().to_string();
+
+    // protocol descriptions, with the device ids that support them
+    let protocols: Vec<(Rp1210Protocol, Vec<&str>)> = ini
         .iter()
-        // find J1939 protocol description
-        .filter(|(section, properties)| {
-            section.unwrap_or("").starts_with("ProtocolInformation")
-                && properties.get("ProtocolString") == Some("J1939")
-        })
-        // which device ids support J1939?
-        .flat_map(|(_, properties)| {
-            properties
-                .get("Devices")
-                .map_or(vec![], |s| s.split(',').collect())
+        .filter(|(section, _)| section.unwrap_or("").starts_with("ProtocolInformation"))
+        .map(|(_, properties)| {
+            (
+                Rp1210Protocol {
+                    protocol: Protocol::from(properties.get("ProtocolString").unwrap_or("")),
+                    description: properties
+                        .get("ProtocolDescription")
+                        .unwrap_or("")
+                        .to_string(),
+                    speeds: properties.get("ProtocolSpeed").map_or(vec![], |s| {
+                        s.split(',').map(|s| s.trim().to_string()).collect()
+                    }),
+                    params: properties.get("ProtocolParams").unwrap_or("").to_string(),
+                },
+                properties
+                    .get("Devices")
+                    .map_or(vec![], |s| s.split(',').map(|d| d.trim()).collect()),
+            )
         })
         .collect();
 
-    // find the specified devices
-    let rtn = ini
+    let devices = ini
         .iter()
-        .filter(|(section, properties)| {
-            section.unwrap().starts_with("DeviceInformation")
-                && j1939_devices.contains(&properties.get("DeviceID").unwrap_or("X"))
+        .filter(|(section, _)| section.unwrap_or("").starts_with("DeviceInformation"))
+        .map(|(_, properties)| {
+            let device_id = properties.get("DeviceID").unwrap_or("").trim();
+            Ok(Rp1210Dev {
+                id: device_id
+                    .parse()
+                    .map_err(|_| anyhow!("invalid DeviceID {:?}", device_id))?,
+                name: properties
+                    .get("DeviceName")
+                    .unwrap_or("Unknown")
+                    .to_string(),
+                description: properties
+                    .get("DeviceDescription")
+                    .unwrap_or("Unknown")
+                    .to_string(),
+                params: properties.get("DeviceParams").unwrap_or("").to_string(),
+                can_channels: properties
+                    .get("MultiCANChannels")
+                    .and_then(|c| c.trim().parse().ok())
+                    .unwrap_or(1),
+                j1939_channels: properties
+                    .get("MultiJ1939Channels")
+                    .and_then(|c| c.trim().parse().ok())
+                    .unwrap_or(1),
+                protocols: protocols
+                    .iter()
+                    .filter(|(_, devices)| devices.contains(&device_id))
+                    .map(|(p, _)| p.clone())
+                    .collect(),
+            })
         })
-        .map(|(_, properties)| Rp1210Dev {
-            id: properties.get("DeviceID").unwrap_or("0").parse().unwrap(),
-            name: properties
-                .get("DeviceName")
-                .unwrap_or("Unknown")
-                .to_string(),
-            description: properties
-                .get("DeviceDescription")
-                .unwrap_or("Unknown")
-                .to_string(),
-        })
-        .collect();
-    println!("  {}.ini parsing in {} ms", id, start.elapsed().as_millis());
-    let description = ini
-        .section(Some("VendorInformation"))
-        .unwrap()
-        .get("Name")
-        .unwrap()
-        .to_string();
-    Ok((description, rtn))
+        .collect::<Result<Vec<Rp1210Dev>>>()?;
+
+    Ok(Rp1210Prod {
+        id: id.to_string(),
+        description: get("Name"),
+        version: get("Version"),
+        rp1210: get("RP1210"),
+        time_stamp_weight: vendor
+            .get("TimeStampWeight")
+            .and_then(|w| w.trim().parse().ok())
+            .unwrap_or(1.0),
+        auto_detect_capable: parse_bool(vendor.get("AutoDetectCapable")),
+        can_auto_baud: parse_bool(vendor.get("CANAutoBaud")),
+        message_string: get("MessageString"),
+        can_formats_supported: get("CANFormatsSupported"),
+        j1939_formats_supported: get("J1939FormatsSupported"),
+        devices,
+    })
 }
 
-#[allow(dead_code)]
-pub fn time_stamp_weight(id: &str) -> Result<f64> {
-    let ini = ini::Ini::load_from_file(&format!("c:\\Windows\\{}.ini", id))?;
-    Ok(ini
-        .get_from_or::<&str>(Some("VendorInformation"), "TimeStampWeight", "1")
-        .parse()?)
+/// One row per device, with a column per common protocol
+pub fn format_table(products: &[Rp1210Prod]) -> String {
+    let mut rtn = format!("{:<12} {:>4} {:<24}", "ADAPTER", "DEV", "NAME");
+    for p in &TABLE_PROTOCOLS {
+        rtn += &format!(" {:^8}", p.to_string());
+    }
+    rtn += " OTHER\n";
+    for prod in products {
+        rtn += &format!(
+            "{:<12} {} (TimeStampWeight={} CANAutoBaud={} MessageString={})\n",
+            prod.id,
+            prod.description,
+            prod.time_stamp_weight,
+            prod.can_auto_baud,
+            if prod.message_string.is_empty() {
+                "-"
+            } else {
+                &prod.message_string
+            }
+        );
+        for d in &prod.devices {
+            rtn += &format!("{:<12} {:>4} {:<24}", "", d.id, d.name);
+            for p in &TABLE_PROTOCOLS {
+                rtn += &format!(" {:^8}", if d.supports(p) { "X" } else { "" });
+            }
+            let other: Vec<String> = d
+                .protocols
+                .iter()
+                .filter(|p| !TABLE_PROTOCOLS.contains(&p.protocol))
+                .map(|p| p.protocol.to_string())
+                .collect();
+            rtn += &format!(" {}\n", other.join(","));
+        }
+    }
+    rtn
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn fixtures() -> IniDirs {
+        IniDirs::new(Some(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/ini"),
+        ))
+    }
+
+    #[test]
+    fn protocol() {
+        assert_eq!(
+            Protocol::J1939,
+            Protocol::from_connection_string("J1939:Baud=Auto")
+        );
+        assert_eq!(
+            Protocol::J1939FD,
+            Protocol::from_connection_string("J1939FD:Baud=500,2000")
+        );
+        assert_eq!(Protocol::CAN, Protocol::from_connection_string("CAN"));
+        assert_eq!("J1939FD", Protocol::J1939FD.to_string());
+        assert_eq!("KWP2000", Protocol::from("KWP2000").to_string());
+    }
+
+    #[test]
+    fn vendor_ini() {
+        let ini = fixtures().load_vendor_ini("EXAMPLE").unwrap();
+        let prod = parse_vendor_ini("EXAMPLE", &ini).unwrap();
+        assert_eq!("Example Adapters Inc.", prod.description);
+        assert_eq!(1000.0, prod.time_stamp_weight);
+        assert!(prod.auto_detect_capable);
+        assert!(!prod.can_auto_baud);
+        assert_eq!("EXAMPLE_MSG", prod.message_string);
+        assert_eq!(2, prod.devices.len());
+
+        let usb = &prod.devices[0];
+        assert_eq!(2, usb.can_channels);
+        assert!(usb.supports(&Protocol::J1939FD));
+        assert!(!usb.supports(&Protocol::J1708));
+        assert_eq!(vec!["250", "500", "Auto"], usb.protocols[0].speeds);
+        assert_eq!("1 USB:Example USB (J1939,J1939FD)", usb.to_string());
+        assert!(prod.devices[1].supports(&Protocol::J1708));
+
+        let table = format_table(&[prod]);
+        assert_eq!(4, table.lines().count());
+        let json = serde_json::to_value(parse_vendor_ini("EXAMPLE", &ini).unwrap()).unwrap();
+        assert_eq!("J1939FD", json["devices"][0]["protocols"][1]["protocol"]);
+    }
+
+    #[test]
+    fn ini_dirs() {
+        let explicit = PathBuf::from("/opt/rp1210");
+        assert_eq!(
+            vec![explicit.clone()],
+            search_dirs(Some(explicit.clone()), Some("/ignored".into()), None)
+        );
+        assert_eq!(
+            vec![PathBuf::from("/env")],
+            search_dirs(None, Some("/env".into()), None)
+        );
+        let defaults = search_dirs(None, None, Some("/home/me".into()));
+        assert!(!defaults.is_empty());
+        if !cfg!(windows) {
+            assert_eq!(PathBuf::from("/home/me/.config/rp1210"), defaults[0]);
+        }
+    }
+
+    #[test]
+    fn list_fixtures() {
+        let dirs = fixtures();
+        // vendor INI names are matched case insensitively
+        assert!(dirs.find("example.INI").is_some());
+        assert!(dirs.find("MISSING.ini").is_none());
+        let products = dirs.list_all_products().unwrap();
+        // the 64 bit list also names a vendor without an INI, which is skipped
+        assert_eq!(1, products.len());
+        assert_eq!("EXAMPLE", products[0].id);
+        assert_eq!(1000.0, dirs.time_stamp_weight("EXAMPLE").unwrap());
+        assert!(dirs.time_stamp_weight("MISSING").is_err());
+
+        let empty = IniDirs::new(Some(PathBuf::from("/nonexistent")));
+        let e = empty.list_all_products().unwrap_err().to_string();
+        assert!(e.contains("/nonexistent"), "{}", e);
+    }
 }
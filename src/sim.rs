@@ -2,26 +2,38 @@ use anyhow::*;
 use std::sync::atomic::*;
 use std::sync::*;
 
+use crate::can::*;
+use crate::command::*;
+use crate::health::*;
+use crate::j1708::*;
 use crate::multiqueue::*;
 use crate::packet::*;
+use crate::rp1210_parsing::{IniDirs, Protocol};
 
+#[allow(dead_code)]
 pub struct Rp1210 {
     pub bus: MultiQueue<J1939Packet>,
+    pub can_bus: MultiQueue<CanFrame>,
+    pub j1708_bus: MultiQueue<J1708Message>,
+    pub events: MultiQueue<Event>,
     pub running: Arc<AtomicBool>,
     pub id: String,
     pub device: i16,
     pub connection_string: String,
+    pub protocol: Protocol,
+    pub reconnect: ReconnectPolicy,
 }
+#[allow(dead_code)]
 impl Rp1210 {
-    #[deprecated(note = "Must be built with Win32 target to user RP1210 adapters.")]
     pub fn new(
         _id: &str,
         _device: i16,
         _connection_string: &str,
         _address: u8,
         _bus: MultiQueue<J1939Packet>,
+        _ini: &IniDirs,
     ) -> Result<Rp1210> {
-        todo!()
+        bail!("Must be built for Windows to use RP1210 adapters.")
     }
     /// background thread to read all packets into queue
     pub fn run(&mut self) {
@@ -32,4 +44,44 @@ impl Rp1210 {
     pub fn send(&self, _packet: &J1939Packet) -> Result<J1939Packet> {
         todo!()
     }
+
+    /// Send raw CAN frame and return frame echoed back from adapter
+    pub fn send_can(&self, _frame: &CanFrame) -> Result<CanFrame> {
+        bail!("Must be built for Windows to use RP1210 adapters.")
+    }
+
+    /// Send J1708 message and return message echoed back from adapter
+    pub fn send_j1708(&self, _message: &J1708Message) -> Result<J1708Message> {
+        bail!("Must be built for Windows to use RP1210 adapters.")
+    }
+
+    /// Send a command built with the command module builders
+    pub fn command(&self, _command: &Command) -> Result<i16> {
+        bail!("Must be built for Windows to use RP1210 adapters.")
+    }
+
+    pub fn hardware_status(&self) -> Result<HardwareStatus> {
+        bail!("Must be built for Windows to use RP1210 adapters.")
+    }
+
+    /// Versions, hardware status and error counts
+    pub fn info(&self) -> Result<AdapterInfo> {
+        bail!("Must be built for Windows to use RP1210 adapters.")
+    }
+
+    /// Stop reading, drain what the adapter already received and wait for the reader and watchdog.
+    pub fn close(&mut self) {
+        unreachable!("new() fails without Windows")
+    }
+
+    /// Estimated adapter clock drift relative to the host, in parts per million
+    pub fn clock_drift(&self) -> Option<f64> {
+        unreachable!("new() fails without Windows")
+    }
+
+    /// Send packet and asynchronously wait for the echo from the adapter
+    #[cfg(feature = "tokio")]
+    pub async fn send_async(&self, _packet: &J1939Packet) -> Result<J1939Packet> {
+        bail!("Must be built for Windows to use RP1210 adapters.")
+    }
 }
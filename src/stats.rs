@@ -0,0 +1,676 @@
+use anyhow::{bail, Result};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+
+/// Width of the longest histogram bar
+const BAR_WIDTH: usize = 50;
+/// Lowest histogram bucket, <= 2^-10 ms
+const MIN_BUCKET: i32 = -10;
+
+/// Round trip times of a ping run, in ms, plus the requests that got no response
+#[derive(Debug, Clone, Default)]
+pub struct LatencyStats {
+    /// (sequence, rtt ms) in the order they were measured
+    samples: Vec<(u32, f64)>,
+    /// sequence numbers that timed out
+    lost: Vec<u32>,
+    /// responses with a lower sequence than one already received
+    out_of_order: usize,
+    /// second response for the same sequence
+    duplicates: usize,
+    /// response after the request was counted as lost
+    late: usize,
+}
+
+#[allow(dead_code)]
+impl LatencyStats {
+    pub fn new() -> LatencyStats {
+        Default::default()
+    }
+
+    pub fn record(&mut self, seq: u32, rtt: f64) {
+        self.samples.push((seq, rtt));
+    }
+
+    pub fn record_lost(&mut self, seq: u32) {
+        self.lost.push(seq);
+    }
+
+    pub fn record_out_of_order(&mut self) {
+        self.out_of_order += 1;
+    }
+
+    pub fn record_duplicate(&mut self) {
+        self.duplicates += 1;
+    }
+
+    pub fn record_late(&mut self) {
+        self.late += 1;
+    }
+
+    pub fn out_of_order(&self) -> usize {
+        self.out_of_order
+    }
+
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+
+    pub fn late(&self) -> usize {
+        self.late
+    }
+
+    pub fn sent(&self) -> usize {
+        self.samples.len() + self.lost.len()
+    }
+
+    pub fn received(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn lost(&self) -> usize {
+        self.lost.len()
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent() == 0 {
+            0.0
+        } else {
+            100.0 * self.lost() as f64 / self.sent() as f64
+        }
+    }
+
+    fn sorted(&self) -> Vec<f64> {
+        let mut rtt: Vec<f64> = self.samples.iter().map(|s| s.1).collect();
+        rtt.sort_by(f64::total_cmp);
+        rtt
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        self.sorted().first().copied()
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        self.sorted().last().copied()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            None
+        } else {
+            Some(self.samples.iter().map(|s| s.1).sum::<f64>() / self.samples.len() as f64)
+        }
+    }
+
+    /// Population standard deviation
+    pub fn std_dev(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        let var = self
+            .samples
+            .iter()
+            .map(|s| (s.1 - mean).powi(2))
+            .sum::<f64>()
+            / self.samples.len() as f64;
+        Some(var.sqrt())
+    }
+
+    /// Nearest rank percentile, p in 0..=100
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        percentile(&self.sorted(), p)
+    }
+
+    /// Text histogram with power of two ms buckets, from the fastest to the slowest sample
+    pub fn histogram(&self) -> String {
+        let sorted = self.sorted();
+        let (Some(first), Some(last)) = (sorted.first(), sorted.last()) else {
+            return String::new();
+        };
+        // bucket upper bounds: ..., 0.25, 0.5, 1, 2, ... ms
+        let bound = |b: i32| 2f64.powi(b);
+        // 0 ms RTTs are common with 1 ms timestamps and go in the lowest bucket
+        let bucket = |rtt: f64| (rtt.max(f64::MIN_POSITIVE).log2().ceil() as i32).max(MIN_BUCKET);
+        let (low, high) = (bucket(*first), bucket(*last));
+        let mut counts = vec![0usize; (high - low + 1) as usize];
+        for rtt in &sorted {
+            counts[(bucket(*rtt) - low) as usize] += 1;
+        }
+        let most = *counts.iter().max().unwrap_or(&1);
+        let mut rtn = String::new();
+        for (i, count) in counts.iter().enumerate() {
+            let bar = (count * BAR_WIDTH).div_ceil(most);
+            rtn.push_str(&format!(
+                "<= {:9.3} ms |{:<width$} {}\n",
+                bound(low + i as i32),
+                "#".repeat(bar),
+                count,
+                width = BAR_WIDTH
+            ));
+        }
+        rtn
+    }
+
+    /// Raw samples as CSV: seq,rtt_ms. Lost requests have an empty rtt.
+    pub fn write_samples(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(out, "seq,rtt_ms")?;
+        let mut rows: Vec<(u32, Option<f64>)> = self
+            .samples
+            .iter()
+            .map(|(seq, rtt)| (*seq, Some(*rtt)))
+            .chain(self.lost.iter().map(|seq| (*seq, None)))
+            .collect();
+        rows.sort_by_key(|r| r.0);
+        for (seq, rtt) in rows {
+            match rtt {
+                Some(rtt) => writeln!(out, "{},{:.4}", seq, rtt)?,
+                None => writeln!(out, "{},", seq)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One tx or rx bandwidth run at a target rate
+#[derive(Debug, Clone, Default)]
+pub struct RateReport {
+    /// frames/s asked for, None for as fast as possible
+    pub target: Option<f64>,
+    /// frames that should have been sent or received
+    pub frames: u32,
+    /// frames echoed (tx) or received (rx)
+    pub received: u32,
+    /// frames with a lower sequence than one already received
+    pub out_of_order: u32,
+    /// frames received with a payload other than the one sent
+    pub corrupt: u32,
+    /// ms from the first to the last frame, adapter clock
+    pub time: f64,
+    /// tx only: send call to echo, host clock
+    pub latency: LatencyStats,
+    /// tx only: adapter echo time minus host send time, relative to the first frame.
+    /// Grows when the adapter queues echoes or its timestamps stall.
+    pub skew: LatencyStats,
+}
+
+impl RateReport {
+    pub const HEADER: &'static str =
+        " target/s achieved/s  frames    lost  loss %  reorder  corrupt   p50 ms   p99 ms   max ms";
+
+    /// frames/s actually sent or received
+    pub fn achieved(&self) -> f64 {
+        if self.time > 0.0 {
+            1000.0 * (self.received.saturating_sub(1)) as f64 / self.time
+        } else {
+            0.0
+        }
+    }
+
+    pub fn lost(&self) -> u32 {
+        self.frames.saturating_sub(self.received)
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.frames == 0 {
+            0.0
+        } else {
+            100.0 * self.lost() as f64 / self.frames as f64
+        }
+    }
+
+    /// Echo timing skew, None without tx samples
+    pub fn skew_line(&self) -> Option<String> {
+        Some(format!(
+            "echo skew min: {:8.4} avg: {:8.4} max: {:8.4} std dev: {:8.4} ms",
+            self.skew.min()?,
+            self.skew.mean()?,
+            self.skew.max()?,
+            self.skew.std_dev()?
+        ))
+    }
+}
+
+impl Display for RateReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ms = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.4}", v));
+        write!(
+            f,
+            "{:>9} {:>10.1} {:>7} {:>7} {:>7.2} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            self.target
+                .map_or("max".to_string(), |t| format!("{:.1}", t)),
+            self.achieved(),
+            self.frames,
+            self.lost(),
+            self.loss_percent(),
+            self.out_of_order,
+            self.corrupt,
+            ms(self.latency.percentile(50.0)),
+            ms(self.latency.percentile(99.0)),
+            ms(self.latency.max()),
+        )
+    }
+}
+
+/// Missing ranges sent in an encoded RxSummary, so it stays a short transport message
+const MAX_SUMMARY_RANGES: usize = 16;
+
+/// Receiver side accounting for a stream of expected frames numbered from 0
+#[derive(Debug, Clone)]
+pub struct SeqTracker {
+    seen: Vec<u64>,
+    highest: Option<u32>,
+    summary: RxSummary,
+}
+
+impl SeqTracker {
+    pub fn new(expected: u32) -> SeqTracker {
+        SeqTracker {
+            seen: Vec::new(),
+            highest: None,
+            summary: RxSummary {
+                expected,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Record a frame received at time (ms). Returns false for duplicates and sequences out of range.
+    pub fn add(&mut self, seq: u32, time: f64) -> bool {
+        let s = &mut self.summary;
+        if s.received + s.duplicates == 0 {
+            s.first = time;
+        }
+        s.last = time;
+        if seq >= s.expected {
+            return false;
+        }
+        let (word, bit) = (seq as usize / 64, 1u64 << (seq % 64));
+        if self.seen.len() <= word {
+            self.seen.resize(word + 1, 0);
+        }
+        if self.seen[word] & bit != 0 {
+            s.duplicates += 1;
+            return false;
+        }
+        self.seen[word] |= bit;
+        s.received += 1;
+        if self.highest.is_some_and(|h| seq < h) {
+            s.out_of_order += 1;
+        }
+        self.highest = self.highest.max(Some(seq));
+        true
+    }
+
+    /// A frame arrived with the wrong payload. Also add() it when its sequence is known.
+    pub fn add_corrupt(&mut self) {
+        self.summary.corrupt += 1;
+    }
+
+    /// every expected frame was received
+    pub fn complete(&self) -> bool {
+        self.summary.received == self.summary.expected
+    }
+
+    pub fn summary(&self) -> RxSummary {
+        let mut summary = self.summary.clone();
+        let mut start = None;
+        for seq in 0..summary.expected {
+            let seen = self
+                .seen
+                .get(seq as usize / 64)
+                .is_some_and(|w| w & (1 << (seq % 64)) != 0);
+            match (seen, start) {
+                (false, None) => start = Some(seq),
+                (true, Some(from)) => {
+                    summary.missing.push((from, seq - 1));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(from) = start {
+            summary.missing.push((from, summary.expected - 1));
+        }
+        summary
+    }
+}
+
+/// What the receiver of a bandwidth test saw
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RxSummary {
+    pub expected: u32,
+    /// unique frames
+    pub received: u32,
+    pub duplicates: u32,
+    pub out_of_order: u32,
+    /// frames whose payload did not verify
+    pub corrupt: u32,
+    /// adapter time of the first and last frame, ms
+    pub first: f64,
+    pub last: f64,
+    /// inclusive ranges of sequences never received. Encoding keeps only the first few.
+    pub missing: Vec<(u32, u32)>,
+}
+
+impl RxSummary {
+    pub fn lost(&self) -> u32 {
+        self.expected.saturating_sub(self.received)
+    }
+
+    pub fn report(&self, target: Option<f64>) -> RateReport {
+        RateReport {
+            target,
+            frames: self.expected,
+            received: self.received,
+            out_of_order: self.out_of_order,
+            corrupt: self.corrupt,
+            time: self.last - self.first,
+            ..Default::default()
+        }
+    }
+
+    /// expected received duplicates out_of_order corrupt:u32, first last:u64 µs, range count:u16, (from to:u32)*
+    pub fn encode(&self) -> Vec<u8> {
+        let ranges = &self.missing[..self.missing.len().min(MAX_SUMMARY_RANGES)];
+        let mut rtn = Vec::with_capacity(38 + 8 * ranges.len());
+        for v in [
+            self.expected,
+            self.received,
+            self.duplicates,
+            self.out_of_order,
+            self.corrupt,
+        ] {
+            rtn.extend_from_slice(&v.to_be_bytes());
+        }
+        for t in [self.first, self.last] {
+            rtn.extend_from_slice(&((t * 1000.0) as u64).to_be_bytes());
+        }
+        rtn.extend_from_slice(&(ranges.len() as u16).to_be_bytes());
+        for (from, to) in ranges {
+            rtn.extend_from_slice(&from.to_be_bytes());
+            rtn.extend_from_slice(&to.to_be_bytes());
+        }
+        rtn
+    }
+
+    pub fn parse(data: &[u8]) -> Result<RxSummary> {
+        if data.len() < 38 {
+            bail!("Summary too short: {} bytes", data.len());
+        }
+        let u32_at = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+        let ms_at =
+            |i: usize| u64::from_be_bytes(data[i..i + 8].try_into().unwrap()) as f64 / 1000.0;
+        let ranges = u16::from_be_bytes([data[36], data[37]]) as usize;
+        if data.len() < 38 + 8 * ranges {
+            bail!(
+                "Summary truncated: {} ranges in {} bytes",
+                ranges,
+                data.len()
+            );
+        }
+        Ok(RxSummary {
+            expected: u32_at(0),
+            received: u32_at(4),
+            duplicates: u32_at(8),
+            out_of_order: u32_at(12),
+            corrupt: u32_at(16),
+            first: ms_at(20),
+            last: ms_at(28),
+            missing: (0..ranges)
+                .map(|r| (u32_at(38 + 8 * r), u32_at(42 + 8 * r)))
+                .collect(),
+        })
+    }
+}
+
+impl Display for RxSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "received: {}/{} lost: {} duplicates: {} out of order: {} corrupt: {} first: {:.4} last: {:.4} ms",
+            self.received,
+            self.expected,
+            self.lost(),
+            self.duplicates,
+            self.out_of_order,
+            self.corrupt,
+            self.first,
+            self.last
+        )?;
+        if !self.missing.is_empty() {
+            let ranges: Vec<String> = self
+                .missing
+                .iter()
+                .map(|(from, to)| {
+                    if from == to {
+                        from.to_string()
+                    } else {
+                        format!("{}-{}", from, to)
+                    }
+                })
+                .collect();
+            let listed: u32 = self.missing.iter().map(|(from, to)| to - from + 1).sum();
+            write!(f, "\nmissing: {}", ranges.join(", "))?;
+            if listed < self.lost() {
+                write!(f, ", ...")?;
+            }
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+impl Display for LatencyStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "sent: {} received: {} lost: {} ({:.2}%)",
+            self.sent(),
+            self.received(),
+            self.lost(),
+            self.loss_percent()
+        )?;
+        if self.out_of_order + self.duplicates + self.late > 0 {
+            writeln!(
+                f,
+                "out of order: {} duplicate: {} late: {}",
+                self.out_of_order, self.duplicates, self.late
+            )?;
+        }
+        let sorted = self.sorted();
+        if let (Some(mean), Some(std_dev)) = (self.mean(), self.std_dev()) {
+            writeln!(
+                f,
+                "rtt min: {:8.4} avg: {:8.4} max: {:8.4} std dev: {:8.4} ms",
+                sorted[0],
+                mean,
+                sorted[sorted.len() - 1],
+                std_dev
+            )?;
+            let p = |p| percentile(&sorted, p).unwrap_or_default();
+            writeln!(
+                f,
+                "rtt p50: {:8.4} p90: {:8.4} p99: {:8.4} p99.9: {:8.4} ms",
+                p(50.0),
+                p(90.0),
+                p(99.0),
+                p(99.9)
+            )?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut stats = LatencyStats::new();
+        assert_eq!(None, stats.percentile(50.0));
+        assert_eq!(None, stats.mean());
+        // 1..=100 ms, recorded out of order
+        (1..=100).rev().for_each(|n| stats.record(n, n as f64));
+        stats.record_lost(101);
+        assert_eq!(Some(1.0), stats.percentile(0.0));
+        assert_eq!(Some(50.0), stats.percentile(50.0));
+        assert_eq!(Some(90.0), stats.percentile(90.0));
+        assert_eq!(Some(99.0), stats.percentile(99.0));
+        assert_eq!(Some(100.0), stats.percentile(99.9));
+        assert_eq!(Some(50.5), stats.mean());
+        assert!((stats.std_dev().unwrap() - 28.866).abs() < 0.001);
+        assert_eq!(101, stats.sent());
+        assert_eq!(1, stats.lost());
+        assert!((stats.loss_percent() - 0.990).abs() < 0.001);
+    }
+
+    #[test]
+    fn histogram() {
+        let mut stats = LatencyStats::new();
+        [0.3, 0.4, 0.45, 0.9, 3.0]
+            .iter()
+            .enumerate()
+            .for_each(|(i, rtt)| stats.record(i as u32, *rtt));
+        let histogram = stats.histogram();
+        let lines: Vec<&str> = histogram.lines().collect();
+        assert_eq!(4, lines.len());
+        assert!(lines[0].starts_with("<=     0.500 ms |#####"));
+        assert!(lines[0].ends_with(" 3"));
+        assert!(lines[2].ends_with(" 0"));
+        assert!(lines[3].starts_with("<=     4.000 ms |"));
+
+        let mut stats = LatencyStats::new();
+        [0.0, 0.0, 1.0]
+            .iter()
+            .enumerate()
+            .for_each(|(i, rtt)| stats.record(i as u32, *rtt));
+        let histogram = stats.histogram();
+        let lines: Vec<&str> = histogram.lines().collect();
+        assert_eq!(11, lines.len());
+        assert!(lines[0].starts_with("<=     0.001 ms |"));
+        assert!(lines[0].ends_with(" 2"));
+        assert!(lines[10].starts_with("<=     1.000 ms |"));
+    }
+
+    #[test]
+    fn samples() {
+        let mut stats = LatencyStats::new();
+        stats.record(2, 1.5);
+        stats.record_lost(1);
+        stats.record(0, 0.25);
+        let mut out = Vec::new();
+        stats.write_samples(&mut out).unwrap();
+        assert_eq!(
+            "seq,rtt_ms\n0,0.2500\n1,\n2,1.5000\n",
+            String::from_utf8(out).unwrap()
+        );
+        assert_eq!(
+            "sent: 3 received: 2 lost: 1 (33.33%)\n\
+             rtt min:   0.2500 avg:   0.8750 max:   1.5000 std dev:   0.6250 ms\n\
+             rtt p50:   0.2500 p90:   1.5000 p99:   1.5000 p99.9:   1.5000 ms\n",
+            stats.to_string()
+        );
+    }
+
+    #[test]
+    fn ordering() {
+        let mut stats = LatencyStats::new();
+        stats.record(2, 1.0);
+        stats.record(1, 1.0);
+        stats.record_out_of_order();
+        stats.record_duplicate();
+        stats.record_lost(3);
+        stats.record_late();
+        assert_eq!(3, stats.sent());
+        assert_eq!(
+            "sent: 3 received: 2 lost: 1 (33.33%)\nout of order: 1 duplicate: 1 late: 1\n",
+            stats
+                .to_string()
+                .lines()
+                .take(2)
+                .collect::<Vec<_>>()
+                .join("\n")
+                + "\n"
+        );
+    }
+
+    #[test]
+    fn rate_report() {
+        let mut report = RateReport {
+            target: Some(100.0),
+            frames: 10,
+            received: 9,
+            out_of_order: 1,
+            time: 80.0,
+            ..Default::default()
+        };
+        assert_eq!(100.0, report.achieved());
+        assert_eq!(1, report.lost());
+        assert_eq!(
+            "    100.0      100.0      10       1   10.00        1        0        -        -        -",
+            report.to_string()
+        );
+        report.target = None;
+        report.latency.record(0, 0.5);
+        assert!(report.to_string().starts_with("      max"));
+        assert!(report.to_string().ends_with("   0.5000   0.5000   0.5000"));
+        assert_eq!(RateReport::HEADER.len(), report.to_string().len());
+        assert_eq!(None, report.skew_line());
+        report.skew.record(0, 0.0);
+        report.skew.record(1, 2.0);
+        assert_eq!(
+            "echo skew min:   0.0000 avg:   1.0000 max:   2.0000 std dev:   1.0000 ms",
+            report.skew_line().unwrap()
+        );
+    }
+
+    #[test]
+    fn seq_tracker() {
+        let mut tracker = SeqTracker::new(10);
+        for seq in [0, 1, 2, 5, 4, 4, 7, 12] {
+            tracker.add(seq, seq as f64);
+        }
+        tracker.add_corrupt();
+        assert!(!tracker.complete());
+        let summary = tracker.summary();
+        assert_eq!(6, summary.received);
+        assert_eq!(4, summary.lost());
+        assert_eq!(1, summary.duplicates);
+        assert_eq!(1, summary.out_of_order);
+        assert_eq!(vec![(3, 3), (6, 6), (8, 9)], summary.missing);
+        assert_eq!(
+            "received: 6/10 lost: 4 duplicates: 1 out of order: 1 corrupt: 1 first: 0.0000 last: 12.0000 ms\n\
+             missing: 3, 6, 8-9",
+            summary.to_string()
+        );
+        assert_eq!(summary, RxSummary::parse(&summary.encode()).unwrap());
+        assert_eq!(12.0, summary.report(None).time);
+        assert_eq!(1, summary.report(None).corrupt);
+
+        (0..10).for_each(|seq| {
+            tracker.add(seq, 20.0);
+        });
+        assert!(tracker.complete());
+        assert!(tracker.summary().missing.is_empty());
+        assert!(RxSummary::parse(&[0; 37]).is_err());
+    }
+
+    #[test]
+    fn summary_ranges() {
+        let mut tracker = SeqTracker::new(100);
+        (0..100).step_by(2).for_each(|seq| {
+            tracker.add(seq, 0.0);
+        });
+        let summary = tracker.summary();
+        assert_eq!(50, summary.missing.len());
+        let parsed = RxSummary::parse(&summary.encode()).unwrap();
+        assert_eq!(MAX_SUMMARY_RANGES, parsed.missing.len());
+        assert!(parsed.to_string().ends_with("29, 31, ..."));
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::*;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+
+use crate::isotp::*;
+
+pub const DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+pub const SECURITY_ACCESS: u8 = 0x27;
+pub const TESTER_PRESENT: u8 = 0x3E;
+pub const READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+pub const READ_DTC_INFORMATION: u8 = 0x19;
+
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+const RESPONSE_PENDING: u8 = 0x78;
+/// suppress positive response bit in the sub-function
+const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+/// ReadDTCInformation reportDTCByStatusMask
+pub const REPORT_DTC_BY_STATUS_MASK: u8 = 0x02;
+
+/// name of a negative response code
+pub fn nrc_name(nrc: u8) -> &'static str {
+    match nrc {
+        0x10 => "generalReject",
+        0x11 => "serviceNotSupported",
+        0x12 => "subFunctionNotSupported",
+        0x13 => "incorrectMessageLengthOrInvalidFormat",
+        0x14 => "responseTooLong",
+        0x21 => "busyRepeatRequest",
+        0x22 => "conditionsNotCorrect",
+        0x24 => "requestSequenceError",
+        0x31 => "requestOutOfRange",
+        0x33 => "securityAccessDenied",
+        0x35 => "invalidKey",
+        0x36 => "exceedNumberOfAttempts",
+        0x37 => "requiredTimeDelayNotExpired",
+        0x78 => "requestCorrectlyReceivedResponsePending",
+        0x7E => "subFunctionNotSupportedInActiveSession",
+        0x7F => "serviceNotSupportedInActiveSession",
+        _ => "unknown",
+    }
+}
+
+/// DTC and status byte from ReadDTCInformation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dtc {
+    pub dtc: u32,
+    pub status: u8,
+}
+
+impl std::fmt::Display for Dtc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:06X} status: {:02X}", self.dtc, self.status)
+    }
+}
+
+/// Parse a reportDTCByStatusMask response: 59 02 availability (dtc[3] status[1])*
+pub fn parse_dtcs(response: &[u8]) -> Result<Vec<Dtc>> {
+    if response.len() < 3 || !(response.len() - 3).is_multiple_of(4) {
+        bail!(
+            "Invalid ReadDTCInformation response length {}",
+            response.len()
+        );
+    }
+    Ok(response[3..]
+        .chunks(4)
+        .map(|c| Dtc {
+            dtc: u32::from_be_bytes([0, c[0], c[1], c[2]]),
+            status: c[3],
+        })
+        .collect())
+}
+
+/// Parse a SecurityAccess requestSeed response: 67 level seed*
+fn parse_seed(level: u8, response: &[u8]) -> Result<&[u8]> {
+    match response {
+        [_, l, seed @ ..] if *l == level => Ok(seed),
+        _ => bail!(
+            "Invalid SecurityAccess response for level {:02X}: {:02X?}",
+            level,
+            response
+        ),
+    }
+}
+
+/// Check a response to service sid. Returns Ok(None) for response pending.
+fn check_response(sid: u8, response: &[u8]) -> Result<Option<()>> {
+    match response {
+        [NEGATIVE_RESPONSE, s, RESPONSE_PENDING, ..] if *s == sid => Ok(None),
+        [NEGATIVE_RESPONSE, s, nrc, ..] if *s == sid => bail!(
+            "Negative response to {:02X}: {:02X} {}",
+            sid,
+            nrc,
+            nrc_name(*nrc)
+        ),
+        [r, ..] if *r == sid + 0x40 => Ok(Some(())),
+        _ => bail!("Unexpected response to {:02X}: {:02X?}", sid, response),
+    }
+}
+
+/// UDS (ISO 14229) client over ISO-TP
+pub struct Uds<'a> {
+    pub isotp: IsoTp<'a>,
+    /// P2*: how long the server may take after a response pending
+    pub p2_star: Duration,
+}
+
+impl<'a> Uds<'a> {
+    pub fn new(isotp: IsoTp<'a>) -> Uds<'a> {
+        Uds {
+            isotp,
+            p2_star: Duration::from_millis(5000),
+        }
+    }
+
+    /// Send a request and return the positive response. Negative responses are errors.
+    pub fn request(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let sid = request[0];
+        let mut rx = self.isotp.request(request, self.p2_star)?;
+        let mut deadline = Instant::now() + self.p2_star;
+        loop {
+            let response = self.isotp.receive(&mut rx)?;
+            if check_response(sid, &response)?.is_some() {
+                return Ok(response);
+            }
+            if Instant::now() > deadline {
+                bail!(
+                    "Response to {:02X} still pending after {:?}",
+                    sid,
+                    self.p2_star
+                );
+            }
+            // each response pending gives the server another P2*
+            deadline = Instant::now() + self.p2_star;
+        }
+    }
+
+    pub fn diagnostic_session_control(&self, session: u8) -> Result<Vec<u8>> {
+        self.request(&[DIAGNOSTIC_SESSION_CONTROL, session])
+    }
+
+    /// Returns the data record, without the echoed identifier
+    pub fn read_data_by_identifier(&self, did: u16) -> Result<Vec<u8>> {
+        let did_bytes = did.to_be_bytes();
+        let response = self.request(&[READ_DATA_BY_IDENTIFIER, did_bytes[0], did_bytes[1]])?;
+        if response.len() < 3 || response[1..3] != did_bytes {
+            bail!("Response for wrong DID: {:02X?}", response);
+        }
+        Ok(response[3..].to_vec())
+    }
+
+    pub fn read_dtcs_by_status_mask(&self, mask: u8) -> Result<Vec<Dtc>> {
+        parse_dtcs(&self.request(&[READ_DTC_INFORMATION, REPORT_DTC_BY_STATUS_MASK, mask])?)
+    }
+
+    /// SecurityAccess with the seed to key hook. level is the odd requestSeed level.
+    pub fn security_access(
+        &self,
+        level: u8,
+        key: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+    ) -> Result<()> {
+        let send_key = match level.checked_add(1) {
+            Some(send_key) if !level.is_multiple_of(2) => send_key,
+            _ => bail!(
+                "SecurityAccess requestSeed level must be odd and below FF: {:02X}",
+                level
+            ),
+        };
+        let response = self.request(&[SECURITY_ACCESS, level])?;
+        let seed = parse_seed(level, &response)?;
+        // an all zero seed means already unlocked
+        if seed.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+        let request: Vec<u8> = [SECURITY_ACCESS, send_key]
+            .into_iter()
+            .chain(key(seed)?)
+            .collect();
+        self.request(&request)?;
+        Ok(())
+    }
+
+    /// TesterPresent, functionally addressed with the positive response suppressed
+    pub fn tester_present(&self) -> Result<()> {
+        self.isotp
+            .send_functional(&[TESTER_PRESENT, SUPPRESS_POSITIVE_RESPONSE])
+    }
+
+    /// Send TesterPresent every period until running is false. Run in a scoped thread.
+    pub fn keep_alive(&self, period: Duration, running: &AtomicBool) -> Result<()> {
+        let mut next = Instant::now();
+        while running.load(Relaxed) {
+            if Instant::now() >= next {
+                self.tester_present()?;
+                next += period;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses() {
+        assert!(check_response(0x22, &[0x62, 0xF1, 0x90]).unwrap().is_some());
+        assert!(check_response(0x22, &[0x7F, 0x22, 0x78]).unwrap().is_none());
+        let e = check_response(0x27, &[0x7F, 0x27, 0x35]).unwrap_err();
+        assert_eq!("Negative response to 27: 35 invalidKey", e.to_string());
+        assert!(check_response(0x22, &[0x50, 0x01]).is_err());
+    }
+
+    #[test]
+    fn dtcs() {
+        let dtcs = parse_dtcs(&[
+            0x59, 0x02, 0xFF, 0x12, 0x34, 0x56, 0x09, 0xAB, 0xCD, 0xEF, 0x28,
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                Dtc {
+                    dtc: 0x123456,
+                    status: 0x09
+                },
+                Dtc {
+                    dtc: 0xABCDEF,
+                    status: 0x28
+                }
+            ],
+            dtcs
+        );
+        assert!(parse_dtcs(&[0x59, 0x02, 0xFF, 0x12]).is_err());
+    }
+
+    #[test]
+    fn seed() {
+        assert_eq!(
+            &[0x12, 0x34],
+            parse_seed(1, &[0x67, 0x01, 0x12, 0x34]).unwrap()
+        );
+        assert!(parse_seed(1, &[0x67, 0x01]).unwrap().is_empty());
+        assert!(parse_seed(1, &[0x67]).is_err());
+        assert!(parse_seed(3, &[0x67, 0x01, 0x12, 0x34]).is_err());
+    }
+}